@@ -61,7 +61,10 @@ fn bench_transfer_one_packet(b: &mut Bencher) {
 
         thread::spawn(move || {
             let mut client = iotry!(UtpSocket::connect(server_addr));
-            iotry!(client.send_to(&data[..]));
+            let mut sent = 0;
+            while sent < data.len() {
+                sent += iotry!(client.send_to(&data[sent..]));
+            }
             iotry!(client.close());
         });
 
@@ -91,7 +94,45 @@ fn bench_transfer_one_megabyte(b: &mut Bencher) {
 
         thread::spawn(move || {
             let mut client = iotry!(UtpSocket::connect(server_addr));
-            iotry!(client.send_to(&data[..]));
+            let mut sent = 0;
+            while sent < data.len() {
+                sent += iotry!(client.send_to(&data[sent..]));
+            }
+            iotry!(client.close());
+        });
+
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok(_) => (),
+                Err(e) => panic!("{}", e)
+            }
+        }
+        iotry!(server.close());
+    });
+    b.bytes = len as u64;
+}
+
+// Long enough for the congestion window to grow to hundreds of packets, which stresses
+// acknowledgement processing in the send window.
+#[bench]
+fn bench_transfer_sixteen_megabytes(b: &mut Bencher) {
+    let len = 16 * 1024 * 1024;
+    let server_addr = next_test_ip4();
+    let mut buf = [0; 1500];
+    let data = (0..len).map(|x| x as u8).collect::<Vec<u8>>();
+    let data_arc = Arc::new(data);
+
+    b.iter(|| {
+        let data = data_arc.clone();
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            let mut sent = 0;
+            while sent < data.len() {
+                sent += iotry!(client.send_to(&data[sent..]));
+            }
             iotry!(client.close());
         });
 
@@ -79,7 +79,7 @@ fn main() {
             loop {
                 match reader.read(&mut payload) {
                     Ok(0) => break,
-                    Ok(read) => stream.write(&payload[..read]).expect("Error writing to stream"),
+                    Ok(read) => stream.write_all(&payload[..read]).expect("Error writing to stream"),
                     Err(e) => {
                         stream.close().expect("Error closing stream");
                         panic!("{:?}", e);
@@ -19,11 +19,6 @@ impl<'a> BitIterator<'a> {
             end_index: obj.len() * U8BITS,
         }
     }
-
-    /// Returns the number of ones in the binary representation of the underlying object.
-    pub fn count_ones(&self) -> u32 {
-        self.object.iter().fold(0, |acc, bv| acc + bv.count_ones())
-    }
 }
 
 impl<'a> Iterator for BitIterator<'a> {
@@ -47,6 +42,44 @@ impl<'a> Iterator for BitIterator<'a> {
 
 impl<'a> ExactSizeIterator for BitIterator<'a> {}
 
+/// Fixed-size bitfield, the writing counterpart of `BitIterator`. Bits are laid out in the same
+/// order, starting with the LSB of the first byte.
+pub struct Bitfield {
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    /// Creates a bitfield with all bits unset, large enough to hold `bytes * 8` bits.
+    pub fn with_bytes(bytes: usize) -> Bitfield {
+        Bitfield { bytes: vec![0; bytes] }
+    }
+
+    /// Returns the number of bits in the bitfield.
+    pub fn len(&self) -> usize {
+        self.bytes.len() * U8BITS
+    }
+
+    /// Sets the bit at position `index`. Returns `false` if the index is out of bounds.
+    pub fn set(&mut self, index: usize) -> bool {
+        if index < self.len() {
+            self.bytes[index / U8BITS] |= 1 << (index % U8BITS);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns whether any bit is set.
+    pub fn any(&self) -> bool {
+        self.bytes.iter().any(|&byte| byte != 0)
+    }
+
+    /// Consumes the bitfield, returning its underlying bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
 #[test]
 fn test_iterator() {
     let bytes = vec![0xCA, 0xFE];
@@ -57,3 +90,24 @@ fn test_iterator() {
         assert_eq!(bit, expected_bits[i] == 1);
     }
 }
+
+#[test]
+fn test_bitfield() {
+    let mut bitfield = Bitfield::with_bytes(2);
+    assert_eq!(bitfield.len(), 16);
+    assert!(!bitfield.any());
+
+    let expected_bits = vec![0, 1, 0, 1, 0, 0, 1, 1, 0, 1, 1, 1, 1, 1, 1, 1];
+    for (i, &bit) in expected_bits.iter().enumerate() {
+        if bit == 1 {
+            assert!(bitfield.set(i));
+        }
+    }
+    assert!(!bitfield.set(16));
+    assert!(bitfield.any());
+
+    let bytes = bitfield.into_bytes();
+    assert_eq!(bytes, vec![0xCA, 0xFE]);
+    assert_eq!(BitIterator::from_bytes(&bytes).map(|bit| bit as i32).collect::<Vec<_>>(),
+               expected_bits);
+}
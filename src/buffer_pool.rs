@@ -0,0 +1,70 @@
+/// A free list of byte buffers, recycled to avoid allocating a new one for every packet.
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+
+    /// Maximum number of buffers kept around for reuse
+    max_buffers: usize,
+
+    /// Capacity of newly allocated buffers
+    buffer_size: usize,
+}
+
+impl BufferPool {
+    /// Creates an empty pool, which will keep at most `max_buffers` buffers of at least
+    /// `buffer_size` bytes around for reuse.
+    pub fn new(max_buffers: usize, buffer_size: usize) -> BufferPool {
+        BufferPool {
+            buffers: Vec::with_capacity(max_buffers),
+            max_buffers,
+            buffer_size,
+        }
+    }
+
+    /// Returns an empty buffer, reusing a pooled one if possible.
+    pub fn get(&mut self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_else(|| Vec::with_capacity(self.buffer_size))
+    }
+
+    /// Returns a buffer to the pool, unless the pool is full or the buffer is too small.
+    pub fn put(&mut self, mut buffer: Vec<u8>) {
+        if self.buffers.len() < self.max_buffers && buffer.capacity() >= self.buffer_size {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use buffer_pool::BufferPool;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let mut pool = BufferPool::new(2, 16);
+        let mut buffer = pool.get();
+        assert!(buffer.capacity() >= 16);
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let ptr = buffer.as_ptr();
+
+        pool.put(buffer);
+        assert_eq!(pool.buffers.len(), 1);
+        let buffer = pool.get();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert_eq!(pool.buffers.len(), 0);
+    }
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let mut pool = BufferPool::new(2, 16);
+        for _ in 0..3 {
+            pool.put(Vec::with_capacity(16));
+        }
+        assert_eq!(pool.buffers.len(), 2);
+
+        // Buffers too small to be useful are dropped
+        pool.get();
+        pool.put(Vec::new());
+        assert_eq!(pool.buffers.len(), 1);
+    }
+}
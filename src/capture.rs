@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65_535;
+const LINKTYPE_RAW: u32 = 101; // raw IPv4 or IPv6 packets, no link layer header
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+const TTL: u8 = 64;
+
+/// A sink recording every datagram sent and received by one or more sockets into a [pcap][pcap]
+/// file.
+///
+/// Each datagram is wrapped in synthesized IP and UDP headers carrying the addresses and ports of
+/// both ends, so the resulting file can be opened directly in Wireshark or tcpdump and decoded
+/// with their uTP dissectors.
+///
+/// Cloning a `PacketCapture` yields a new handle to the *same* file, so a single capture may be
+/// shared by several sockets, and by every socket accepted by a `UtpListener`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::{PacketCapture, UtpSocket};
+///
+/// let capture = PacketCapture::create("utp.pcap").expect("Error creating capture file");
+/// let mut socket = UtpSocket::connect("127.0.0.1:8080").expect("Error connecting");
+/// socket.set_capture(Some(capture));
+/// ```
+///
+/// [pcap]: https://wiki.wireshark.org/Development/LibpcapFileFormat
+#[derive(Clone)]
+pub struct PacketCapture {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl PacketCapture {
+    /// Creates a capture writing to a new file at `path`, truncating it if it already exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<PacketCapture> {
+        let file = try!(File::create(path));
+        PacketCapture::new(BufWriter::new(file))
+    }
+
+    /// Creates a capture writing to `writer`.
+    ///
+    /// The pcap file header is written immediately.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> io::Result<PacketCapture> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // GMT to local correction
+        header.extend_from_slice(&0u32.to_le_bytes()); // accuracy of timestamps
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        try!(writer.write_all(&header));
+
+        Ok(PacketCapture {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+        })
+    }
+
+    /// Records a datagram sent from `src` to `dst`, timestamped with the current time.
+    pub fn record(&self, src: SocketAddr, dst: SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|e| e.duration());
+        let packet = encapsulate(src, dst, datagram);
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&(now.subsec_micros()).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&record)
+    }
+
+    /// Flushes buffered records to the underlying writer.
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+/// Wraps a datagram in IP and UDP headers.
+///
+/// Addresses of different families are mapped to IPv6, so both ends always share a header.
+fn encapsulate(src: SocketAddr, dst: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    let udp_len = UDP_HEADER_SIZE + datagram.len();
+    let mut packet = Vec::with_capacity(IPV6_HEADER_SIZE + udp_len);
+
+    let pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = [0u8; IPV4_HEADER_SIZE];
+            header[0] = 0x45; // version 4, header length of 5 words
+            header[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + udp_len) as u16).to_be_bytes());
+            header[6] = 0x40; // don't fragment
+            header[8] = TTL;
+            header[9] = UDP_PROTOCOL;
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = !fold(sum_words(&header));
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.extend_from_slice(&header);
+
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&src_ip.octets());
+            pseudo_header.extend_from_slice(&dst_ip.octets());
+            pseudo_header.extend_from_slice(&[0, UDP_PROTOCOL]);
+            pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            pseudo_header
+        }
+        (src_ip, dst_ip) => {
+            let (src_ip, dst_ip) = (to_ipv6(src_ip), to_ipv6(dst_ip));
+            let mut header = [0u8; IPV6_HEADER_SIZE];
+            header[0] = 0x60; // version 6
+            header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+            header[6] = UDP_PROTOCOL;
+            header[7] = TTL;
+            header[8..24].copy_from_slice(&src_ip.octets());
+            header[24..40].copy_from_slice(&dst_ip.octets());
+            packet.extend_from_slice(&header);
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&src_ip.octets());
+            pseudo_header.extend_from_slice(&dst_ip.octets());
+            pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, UDP_PROTOCOL]);
+            pseudo_header
+        }
+    };
+
+    let mut udp_header = [0u8; UDP_HEADER_SIZE];
+    udp_header[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp_header[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    udp_header[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    let sum = sum_words(&pseudo_header) + sum_words(&udp_header) + sum_words(datagram);
+    // A computed checksum of zero is transmitted as all ones
+    let checksum = match !fold(sum) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp_header[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp_header);
+    packet.extend_from_slice(datagram);
+
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> ::std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Sums the big-endian 16-bit words of `data`, padding it with a zero byte if needed.
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2).fold(0, |acc, word| {
+        acc + ((word[0] as u32) << 8 | word.get(1).cloned().unwrap_or(0) as u32)
+    })
+}
+
+/// Folds a 32-bit sum of 16-bit words into a one's complement 16-bit sum.
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use capture::{PacketCapture, encapsulate, fold, sum_words};
+
+    /// A writer into a buffer that the test can still inspect after handing it over.
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ipv4_encapsulation() {
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let datagram = [1, 2, 3];
+        let packet = encapsulate(src, dst, &datagram);
+
+        assert_eq!(packet.len(), 20 + 8 + 3);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(&packet[2..4], &[0, 31]);
+        assert_eq!(packet[9], 17);
+        assert_eq!(&packet[12..16], &[127, 0, 0, 1]);
+        assert_eq!(&packet[16..20], &[10, 0, 0, 1]);
+        // A valid header sums to all ones
+        assert_eq!(fold(sum_words(&packet[..20])), 0xffff);
+
+        // UDP ports, length and payload
+        assert_eq!(&packet[20..22], &[0x04, 0xd2]);
+        assert_eq!(&packet[22..24], &[0x1a, 0xe1]);
+        assert_eq!(&packet[24..26], &[0, 11]);
+        assert_eq!(&packet[28..], &datagram);
+
+        // The UDP checksum covers a pseudo header with the addresses, protocol and length
+        let pseudo_header = [127, 0, 0, 1, 10, 0, 0, 1, 0, 17, 0, 11];
+        assert_eq!(fold(sum_words(&pseudo_header) + sum_words(&packet[20..])), 0xffff);
+    }
+
+    #[test]
+    fn test_ipv6_encapsulation() {
+        let src: SocketAddr = "[::1]:1234".parse().unwrap();
+        let dst: SocketAddr = "[::1]:6881".parse().unwrap();
+        let packet = encapsulate(src, dst, &[1, 2, 3, 4]);
+
+        assert_eq!(packet.len(), 40 + 8 + 4);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(&packet[4..6], &[0, 12]);
+        assert_eq!(packet[6], 17);
+        assert_eq!(packet[23], 1);
+        assert_eq!(packet[39], 1);
+        assert_eq!(&packet[48..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_capture_file_layout() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let capture = PacketCapture::new(buffer.clone()).unwrap();
+        let src: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let dst: SocketAddr = "127.0.0.1:4321".parse().unwrap();
+        capture.record(src, dst, &[0; 20]).unwrap();
+        capture.clone().record(dst, src, &[0; 26]).unwrap();
+
+        let bytes = buffer.0.lock().unwrap();
+        // Global header: magic number, version 2.4 and raw IP link type
+        assert_eq!(&bytes[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&bytes[4..8], &[2, 0, 4, 0]);
+        assert_eq!(&bytes[20..24], &[101, 0, 0, 0]);
+
+        // Two records, each with a 16 bytes header followed by the IP packet
+        let first_len = 20 + 8 + 20;
+        let second_len = 20 + 8 + 26;
+        assert_eq!(bytes.len(), 24 + 16 + first_len + 16 + second_len);
+        assert_eq!(&bytes[24 + 8..24 + 12], &[first_len as u8, 0, 0, 0]);
+        assert_eq!(&bytes[24 + 12..24 + 16], &[first_len as u8, 0, 0, 0]);
+        let second = 24 + 16 + first_len;
+        assert_eq!(&bytes[second + 8..second + 12], &[second_len as u8, 0, 0, 0]);
+    }
+}
@@ -2,59 +2,115 @@ use std::error::Error;
 use std::fmt;
 use std::io::{self, ErrorKind};
 
-#[derive(Debug)]
-pub enum SocketError {
+/// Errors specific to uTP.
+///
+/// Sockets and streams report failures as `io::Error`s, with a `UtpError` inside whenever the
+/// failure is specific to the protocol. It can be retrieved to tell, for instance, a connection
+/// reset by the remote peer from a timeout:
+///
+/// ```no_run
+/// use utp::{UtpError, UtpSocket};
+///
+/// match UtpSocket::connect("127.0.0.1:8080") {
+///     Ok(_socket) => println!("Connected"),
+///     Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<UtpError>()) {
+///         Some(&UtpError::HandshakeTimeout) => println!("The remote peer didn't answer"),
+///         Some(&UtpError::ConnectionReset) => println!("The remote peer refused"),
+///         _ => println!("Error connecting: {}", e),
+///     },
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UtpError {
+    /// The socket is closed
     ConnectionClosed,
+    /// The connection was aborted locally
+    ConnectionAborted,
+    /// The remote peer reset the connection
     ConnectionReset,
-    ConnectionTimedOut,
+    /// The remote peer didn't answer the connection request
+    HandshakeTimeout,
+    /// The remote peer stopped acknowledging packets
+    RetransmissionTimeout,
+    /// The address doesn't resolve to any socket address
     InvalidAddress,
+    /// A received packet couldn't be decoded
+    InvalidPacket(ParseError),
+    /// The remote peer answered with a packet of an unexpected type
     InvalidReply,
+    /// The remote peer answered with a connection identifier other than the expected one
+    ConnectionIdMismatch,
+    /// The socket is not connected
     NotConnected,
+    /// Any other failure
     Other(String),
 }
 
-impl Error for SocketError {
+impl Error for UtpError {
     fn description(&self) -> &str {
-        use self::SocketError::*;
+        use self::UtpError::*;
         match *self {
-            ConnectionClosed   => "The socket is closed",
-            ConnectionReset    => "Connection reset by remote peer",
-            ConnectionTimedOut => "Connection timed out",
-            InvalidAddress     => "Invalid address",
-            InvalidReply       => "The remote peer sent an invalid reply",
-            NotConnected       => "The socket is not connected",
+            ConnectionClosed      => "The socket is closed",
+            ConnectionAborted     => "The connection was aborted",
+            ConnectionReset       => "Connection reset by remote peer",
+            HandshakeTimeout      => "Connection request timed out",
+            RetransmissionTimeout => "Connection timed out",
+            InvalidAddress        => "Invalid address",
+            InvalidPacket(_)      => "Invalid packet",
+            InvalidReply          => "The remote peer sent an invalid reply",
+            ConnectionIdMismatch  => "The remote peer sent an unexpected connection id",
+            NotConnected          => "The socket is not connected",
             Other(ref s) => s,
         }
     }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            UtpError::InvalidPacket(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
-impl fmt::Display for SocketError {
+impl fmt::Display for UtpError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(self.description())
+        match *self {
+            UtpError::InvalidPacket(ref e) => write!(f, "{}: {}", self.description(), e),
+            _ => f.write_str(self.description()),
+        }
     }
 }
 
-impl From<SocketError> for io::Error {
-    fn from(error: SocketError) -> io::Error {
-        use self::SocketError::*;
+impl From<UtpError> for io::Error {
+    fn from(error: UtpError) -> io::Error {
+        use self::UtpError::*;
         let kind = match error {
             ConnectionClosed |
-            NotConnected       => ErrorKind::NotConnected,
-            ConnectionReset    => ErrorKind::ConnectionReset,
-            ConnectionTimedOut => ErrorKind::TimedOut,
-            InvalidAddress     => ErrorKind::InvalidInput,
-            InvalidReply       => ErrorKind::ConnectionRefused,
-            Other(_)           => ErrorKind::Other,
+            NotConnected          => ErrorKind::NotConnected,
+            ConnectionAborted     => ErrorKind::ConnectionAborted,
+            ConnectionReset       => ErrorKind::ConnectionReset,
+            HandshakeTimeout |
+            RetransmissionTimeout => ErrorKind::TimedOut,
+            InvalidAddress        => ErrorKind::InvalidInput,
+            InvalidPacket(_) |
+            InvalidReply |
+            ConnectionIdMismatch  => ErrorKind::InvalidData,
+            Other(_)              => ErrorKind::Other,
         };
-        io::Error::new(kind, error.description())
+        io::Error::new(kind, error)
     }
 }
 
-#[derive(Debug)]
+/// Errors that may occur when decoding a packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ParseError {
+    /// An extension's length is zero, not a multiple of 4, or exceeds the packet
     InvalidExtensionLength,
+    /// The packet is shorter than its header and extensions
     InvalidPacketLength,
+    /// The packet's type is unknown
     InvalidPacketType(u8),
+    /// The packet's protocol version isn't 1
     UnsupportedVersion,
 }
 
@@ -78,6 +134,6 @@ impl Error for ParseError {
 
 impl From<ParseError> for io::Error {
     fn from(error: ParseError) -> io::Error {
-        io::Error::new(ErrorKind::Other, error.description())
+        UtpError::InvalidPacket(error).into()
     }
 }
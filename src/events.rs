@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+
+/// The reason a connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection was shut down with an exchange of `Fin` packets
+    Graceful,
+
+    /// The remote peer reset the connection
+    Reset,
+
+    /// The remote peer stopped acknowledging packets
+    TimedOut,
+
+    /// The connection was aborted locally, with a `Reset` packet
+    Aborted,
+}
+
+/// A handler notified of the lifecycle events of a connection as they happen.
+///
+/// Every callback receives the address of the remote peer. Callbacks run synchronously on the
+/// thread using the socket, so they should return quickly. Every method has an empty default
+/// implementation, so handlers only need to implement the events they're interested in.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::net::SocketAddr;
+/// use std::sync::Arc;
+/// use utp::{CloseReason, EventHandler, UtpListener};
+///
+/// struct Swarm;
+///
+/// impl EventHandler for Swarm {
+///     fn on_connected(&self, peer: SocketAddr) {
+///         println!("{} joined", peer);
+///     }
+///
+///     fn on_closed(&self, peer: SocketAddr, reason: CloseReason) {
+///         println!("{} left: {:?}", peer, reason);
+///     }
+/// }
+///
+/// let mut listener = UtpListener::bind("0.0.0.0:6881").expect("Error binding listener");
+/// listener.set_event_handler(Some(Arc::new(Swarm)));
+/// ```
+pub trait EventHandler: Send + Sync {
+    /// Called when the handshake with the remote peer completes.
+    fn on_connected(&self, _peer: SocketAddr) {}
+
+    /// Called when the remote peer finishes sending, with a `Fin` packet.
+    fn on_fin_received(&self, _peer: SocketAddr) {}
+
+    /// Called when the remote peer resets the connection.
+    fn on_reset_received(&self, _peer: SocketAddr) {}
+
+    /// Called whenever the retransmission timer expires without any reply from the remote peer.
+    fn on_timeout(&self, _peer: SocketAddr) {}
+
+    /// Called when acknowledgements reveal lost packets, which halves the congestion window.
+    fn on_loss(&self, _peer: SocketAddr) {}
+
+    /// Called once when the connection closes.
+    fn on_closed(&self, _peer: SocketAddr, _reason: CloseReason) {}
+}
@@ -64,11 +64,31 @@ extern crate num_traits;
 pub use socket::UtpSocket;
 pub use socket::UtpListener;
 pub use stream::UtpStream;
+pub use rate_limit::RateLimiter;
+pub use capture::PacketCapture;
+pub use error::{ParseError, UtpError};
+pub use events::{CloseReason, EventHandler};
+pub use metrics::ListenerMetrics;
+pub use observer::{DropReason, PacketObserver};
+pub use stats::SocketStats;
 
 mod bit_iterator;
+mod buffer_pool;
+mod capture;
 mod error;
+mod events;
+mod metrics;
+mod observer;
+#[cfg(feature = "packet")]
+pub mod packet;
+#[cfg(not(feature = "packet"))]
 mod packet;
+mod rate_limit;
+mod seq_buffer;
 mod socket;
+mod stats;
 mod stream;
+mod syn_cookie;
 mod time;
+mod time_wait;
 mod util;
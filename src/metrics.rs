@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide counters of a `UtpListener` and of every socket it accepted.
+///
+/// Cloning a `ListenerMetrics` yields a new handle to the *same* counters, so it can be handed
+/// to the thread serving the metrics while the listener keeps accepting connections.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::UtpListener;
+///
+/// let listener = UtpListener::bind("0.0.0.0:6881").expect("Error binding listener");
+/// let metrics = listener.metrics();
+///
+/// // Serve this from an HTTP endpoint
+/// let body = metrics.render_prometheus();
+/// ```
+#[derive(Clone)]
+pub struct ListenerMetrics {
+    counters: Arc<Counters>,
+}
+
+/// Counters shared by a listener and the sockets it accepted.
+pub struct Counters {
+    handshakes_accepted: AtomicU64,
+    handshakes_rejected: AtomicU64,
+    unknown_packets: AtomicU64,
+    invalid_packets: AtomicU64,
+    resets_sent: AtomicU64,
+    resets_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    retransmissions: AtomicU64,
+
+    /// Number of live accepted sockets in each state
+    states: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Counters {
+    /// Creates a new set of counters, all zero.
+    pub fn new() -> Counters {
+        Counters {
+            handshakes_accepted: AtomicU64::new(0),
+            handshakes_rejected: AtomicU64::new(0),
+            unknown_packets: AtomicU64::new(0),
+            invalid_packets: AtomicU64::new(0),
+            resets_sent: AtomicU64::new(0),
+            resets_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            retransmissions: AtomicU64::new(0),
+            states: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Accounts for a datagram sent by the listener or an accepted socket.
+    pub fn add_sent(&self, bytes: usize, retransmission: bool) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        if retransmission {
+            self.retransmissions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Accounts for a datagram received by the listener or an accepted socket.
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a connection established by the listener.
+    pub fn handshake_accepted(&self) {
+        self.handshakes_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a handshake the listener couldn't turn into a connection.
+    pub fn handshake_rejected(&self) {
+        self.handshakes_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a packet of a connection the listener doesn't know about.
+    pub fn unknown_packet(&self) {
+        self.unknown_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram that couldn't be decoded as a uTP packet.
+    pub fn invalid_packet(&self) {
+        self.invalid_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a `Reset` packet sent to a remote peer.
+    pub fn reset_sent(&self) {
+        self.resets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a `Reset` packet received from a remote peer.
+    pub fn reset_received(&self) {
+        self.resets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn enter(&self, state: &'static str) {
+        *self.states.lock().unwrap().entry(state).or_insert(0) += 1;
+    }
+
+    fn leave(&self, state: &'static str) {
+        if let Some(count) = self.states.lock().unwrap().get_mut(state) {
+            *count -= 1;
+        }
+    }
+}
+
+/// An accepted socket's handle on the counters of its listener, which keeps the per-state
+/// connection counts up to date for as long as the socket lives.
+pub struct ConnectionMetrics {
+    counters: Arc<Counters>,
+    state: &'static str,
+}
+
+impl ConnectionMetrics {
+    /// Registers a new connection in the given state.
+    pub fn new(counters: Arc<Counters>, state: &'static str) -> ConnectionMetrics {
+        counters.enter(state);
+        ConnectionMetrics {
+            counters,
+            state,
+        }
+    }
+
+    /// Moves the connection to another state.
+    pub fn set_state(&mut self, state: &'static str) {
+        if state != self.state {
+            self.counters.leave(self.state);
+            self.counters.enter(state);
+            self.state = state;
+        }
+    }
+
+    /// Returns the shared counters.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        self.counters.leave(self.state);
+    }
+}
+
+/// Returns a public handle on a listener's counters.
+pub fn listener_metrics(counters: &Arc<Counters>) -> ListenerMetrics {
+    ListenerMetrics { counters: counters.clone() }
+}
+
+impl ListenerMetrics {
+    /// Renders every metric in the [Prometheus text exposition format][format].
+    ///
+    /// Connection counts only cover the sockets accepted by the listener that are still alive,
+    /// and are broken down by state. Every other metric is a counter covering the listener's
+    /// whole lifetime.
+    ///
+    /// [format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render_prometheus(&self) -> String {
+        let counters = &self.counters;
+        let states = counters.states.lock().unwrap().clone();
+        let closed = ["closed", "reset_received", "aborted"];
+        let active = states.iter()
+                           .filter(|&(state, _)| !closed.contains(state))
+                           .map(|(_, n)| n)
+                           .sum();
+
+        let mut out = String::new();
+        write_metric(&mut out, "utp_connections_active", "gauge",
+                     "Accepted connections not closed yet.", active);
+        write_header(&mut out, "utp_connections", "gauge", "Accepted connections by state.");
+        for (state, count) in &states {
+            let _ = writeln!(out, "utp_connections{{state=\"{}\"}} {}", state, count);
+        }
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        write_metric(&mut out, "utp_handshakes_accepted_total", "counter",
+                     "Connections accepted.", load(&counters.handshakes_accepted));
+        write_metric(&mut out, "utp_handshakes_rejected_total", "counter",
+                     "Handshakes the listener couldn't turn into a connection.",
+                     load(&counters.handshakes_rejected));
+        write_metric(&mut out, "utp_unknown_packets_total", "counter",
+                     "Packets of connections the listener doesn't know about.",
+                     load(&counters.unknown_packets));
+        write_metric(&mut out, "utp_invalid_packets_total", "counter",
+                     "Datagrams that couldn't be decoded as uTP packets.",
+                     load(&counters.invalid_packets));
+        write_metric(&mut out, "utp_resets_sent_total", "counter",
+                     "Reset packets sent.", load(&counters.resets_sent));
+        write_metric(&mut out, "utp_resets_received_total", "counter",
+                     "Reset packets received.", load(&counters.resets_received));
+        write_metric(&mut out, "utp_sent_bytes_total", "counter",
+                     "Bytes sent, headers and retransmissions included.",
+                     load(&counters.bytes_sent));
+        write_metric(&mut out, "utp_received_bytes_total", "counter",
+                     "Bytes received, headers included.", load(&counters.bytes_received));
+        write_metric(&mut out, "utp_retransmissions_total", "counter",
+                     "Packets sent more than once.", load(&counters.retransmissions));
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    write_header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use metrics::{ConnectionMetrics, Counters, listener_metrics};
+
+    #[test]
+    fn test_render_prometheus() {
+        let counters = Arc::new(Counters::new());
+        let metrics = listener_metrics(&counters);
+        counters.handshake_accepted();
+        counters.add_sent(100, false);
+        counters.add_sent(50, true);
+
+        let mut first = ConnectionMetrics::new(counters.clone(), "new");
+        first.set_state("connected");
+        let second = ConnectionMetrics::new(counters.clone(), "connected");
+        let mut third = ConnectionMetrics::new(counters.clone(), "connected");
+        third.set_state("closed");
+
+        let out = metrics.render_prometheus();
+        assert!(out.contains("# TYPE utp_connections_active gauge\nutp_connections_active 2\n"));
+        assert!(out.contains("utp_connections{state=\"connected\"} 2\n"));
+        assert!(out.contains("utp_connections{state=\"closed\"} 1\n"));
+        assert!(out.contains("utp_connections{state=\"new\"} 0\n"));
+        assert!(out.contains("# TYPE utp_handshakes_accepted_total counter\n"));
+        assert!(out.contains("utp_handshakes_accepted_total 1\n"));
+        assert!(out.contains("utp_sent_bytes_total 150\n"));
+        assert!(out.contains("utp_retransmissions_total 1\n"));
+
+        // Dropped sockets no longer count
+        drop(first);
+        drop(second);
+        drop(third);
+        let out = metrics.render_prometheus();
+        assert!(out.contains("utp_connections_active 0\n"));
+        assert!(out.contains("utp_connections{state=\"closed\"} 0\n"));
+    }
+}
@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+
+/// The reason an incoming packet was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// The datagram couldn't be decoded as a uTP packet
+    Malformed,
+
+    /// The packet doesn't belong to the connection it was sent to
+    WrongConnectionId,
+
+    /// The packet was received before, or was already read
+    Duplicate,
+
+    /// The incoming buffer had no room for the packet, which wasn't acknowledged
+    BufferFull,
+}
+
+/// A hook notified of every packet a socket sends, receives or drops.
+///
+/// Observers make it possible to build tracing, metrics or test assertions on top of the packet
+/// flow. Packets are passed as raw datagrams, which can be decoded with the types in the `packet`
+/// module when the `packet` feature is enabled.
+///
+/// Callbacks run synchronously on the thread using the socket, so they should return quickly.
+/// Every method has an empty default implementation, so observers only need to implement the
+/// callbacks they're interested in.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::net::SocketAddr;
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use utp::{PacketObserver, UtpSocket};
+///
+/// #[derive(Default)]
+/// struct Retransmissions(AtomicUsize);
+///
+/// impl PacketObserver for Retransmissions {
+///     fn on_sent(&self, _datagram: &[u8], _dst: SocketAddr, retransmission: bool) {
+///         if retransmission {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+/// }
+///
+/// let observer = Arc::new(Retransmissions::default());
+/// let mut socket = UtpSocket::connect("127.0.0.1:8080").expect("Error connecting");
+/// socket.set_observer(Some(observer.clone()));
+/// ```
+pub trait PacketObserver: Send + Sync {
+    /// Called after a datagram is sent to `dst`. `retransmission` is true if the packet was sent
+    /// before.
+    fn on_sent(&self, _datagram: &[u8], _dst: SocketAddr, _retransmission: bool) {}
+
+    /// Called for every datagram received from `src`, before it is processed.
+    fn on_received(&self, _datagram: &[u8], _src: SocketAddr) {}
+
+    /// Called when a datagram received from `src` is discarded. `on_received` was called for it
+    /// beforehand.
+    fn on_dropped(&self, _datagram: &[u8], _src: SocketAddr, _reason: DropReason) {}
+}
@@ -1,33 +1,135 @@
+//! Encoding and decoding of uTP packets, as described in [BEP 29][spec].
+//!
+//! This module is only public with the `packet` feature enabled. It's meant for tools that deal
+//! with raw packets, such as packet inspectors, protocol dissectors, or test harnesses injecting
+//! crafted packets.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "packet")]
+//! # fn main() {
+//! use utp::packet::{Packet, PacketRef, PacketType, ExtensionType, TryFrom};
+//!
+//! let mut packet = Packet::new();
+//! packet.set_type(PacketType::State);
+//! packet.set_seq_nr(1);
+//! packet.set_ack_nr(41);
+//! packet.set_sack(vec![0b101, 0, 0, 0]);
+//!
+//! let decoded = PacketRef::try_from(packet.as_ref()).expect("Invalid packet");
+//! assert_eq!(decoded.get_type(), PacketType::State);
+//! assert_eq!(decoded.ack_nr(), 41);
+//! for extension in decoded.extensions() {
+//!     assert_eq!(extension.get_type(), ExtensionType::SelectiveAck);
+//!     assert_eq!(extension.iter().take(3).collect::<Vec<_>>(), vec![true, false, true]);
+//! }
+//! # }
+//! # #[cfg(not(feature = "packet"))]
+//! # fn main() {}
+//! ```
+//!
+//! [spec]: http://www.bittorrent.org/beps/bep_0029.html
 #![allow(dead_code)]
 
-use bit_iterator::BitIterator;
-use error::ParseError;
 use std::fmt;
-use time::{Timestamp, Delay};
 
+pub use bit_iterator::BitIterator;
+pub use error::ParseError;
+pub use time::{Timestamp, Delay};
+
+/// Size in bytes of a packet header, without extensions.
 pub const HEADER_SIZE: usize = 20;
 
-macro_rules! u8_to_unsigned_be {
-    ($src:ident, $start:expr, $end:expr, $t:ty) => ({
-        (0 .. $end - $start + 1).rev().fold(0, |acc, i| acc | $src[$start+i] as $t << (i * 8))
-    })
-}
+// Byte offsets of the header fields, all of which are in network (big-endian) byte order
+const TYPE_VER_OFFSET: usize = 0;
+const EXTENSION_OFFSET: usize = 1;
+const CONNECTION_ID_OFFSET: usize = 2;
+const TIMESTAMP_OFFSET: usize = 4;
+const TIMESTAMP_DIFFERENCE_OFFSET: usize = 8;
+const WND_SIZE_OFFSET: usize = 12;
+const SEQ_NR_OFFSET: usize = 16;
+const ACK_NR_OFFSET: usize = 18;
 
 macro_rules! make_getter {
-    ($name:ident, $t:ty, $m:ident) => {
+    ($(#[$attr:meta])* $name:ident, $t:ty, $read:ident, $offset:expr) => {
+        $(#[$attr])*
         pub fn $name(&self) -> $t {
-            let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-            $m::from_be(header.$name)
+            $read(self.as_ref(), $offset).into()
         }
     }
 }
 
 macro_rules! make_setter {
-    ($fn_name:ident, $field:ident, $t: ty) => {
+    ($(#[$attr:meta])* $fn_name:ident, $t:ty, $write:ident, $offset:expr) => {
+        $(#[$attr])*
         pub fn $fn_name(&mut self, new: $t) {
-            let mut header = unsafe { &mut*(self.0.as_mut_ptr() as *mut PacketHeader) };
-            header.$field = new.to_be();
+            $write(self.bytes_mut(), $offset, new.into());
+        }
+    }
+}
+
+/// Implements the header getters for a type whose bytes (`AsRef<[u8]>`) are a valid packet.
+macro_rules! make_header_getters {
+    () => {
+        /// Returns the packet's type.
+        pub fn get_type(&self) -> PacketType {
+            PacketType::try_from(self.as_ref()[TYPE_VER_OFFSET] >> 4).unwrap()
         }
+
+        /// Returns the protocol version, which is always 1.
+        pub fn get_version(&self) -> u8 {
+            self.as_ref()[TYPE_VER_OFFSET] & 0x0F
+        }
+
+        /// Returns the type of the first extension, or `ExtensionType::None` if there are none.
+        pub fn get_extension_type(&self) -> ExtensionType {
+            ExtensionType::from(self.as_ref()[EXTENSION_OFFSET])
+        }
+
+        make_getter!(
+            /// Returns the connection identifier.
+            connection_id, u16, read_u16, CONNECTION_ID_OFFSET);
+        make_getter!(
+            /// Returns the time the packet was sent at, in microseconds.
+            timestamp, Timestamp, read_u32, TIMESTAMP_OFFSET);
+        make_getter!(
+            /// Returns the sender's latest measurement of the one-way delay from the receiver, in
+            /// microseconds.
+            timestamp_difference, Delay, read_u32, TIMESTAMP_DIFFERENCE_OFFSET);
+        make_getter!(
+            /// Returns the sender's advertised receive window, in bytes.
+            wnd_size, u32, read_u32, WND_SIZE_OFFSET);
+        make_getter!(
+            /// Returns the packet's sequence number.
+            seq_nr, u16, read_u16, SEQ_NR_OFFSET);
+        make_getter!(
+            /// Returns the sequence number of the latest packet the sender received in order.
+            ack_nr, u16, read_u16, ACK_NR_OFFSET);
+    }
+}
+
+/// Implements the header setters for a type with a `bytes_mut` method returning its bytes.
+macro_rules! make_header_setters {
+    () => {
+        make_setter!(
+            /// Sets the connection identifier.
+            set_connection_id, u16, write_u16, CONNECTION_ID_OFFSET);
+        make_setter!(
+            /// Sets the time the packet was sent at, in microseconds.
+            set_timestamp, Timestamp, write_u32, TIMESTAMP_OFFSET);
+        make_setter!(
+            /// Sets the one-way delay measurement, in microseconds.
+            set_timestamp_difference, Delay, write_u32, TIMESTAMP_DIFFERENCE_OFFSET);
+        make_setter!(
+            /// Sets the advertised receive window, in bytes.
+            set_wnd_size, u32, write_u32, WND_SIZE_OFFSET);
+        make_setter!(
+            /// Sets the packet's sequence number.
+            set_seq_nr, u16, write_u16, SEQ_NR_OFFSET);
+        make_setter!(
+            /// Sets the acknowledgement number.
+            set_ack_nr, u16, write_u16, ACK_NR_OFFSET);
     }
 }
 
@@ -35,17 +137,26 @@ macro_rules! make_setter {
 ///
 /// Waiting for rust-lang/rust#33417 to become stable.
 pub trait TryFrom<T>: Sized {
+    /// The type returned in the event of a conversion error.
     type Err;
+
+    /// Performs the conversion.
     fn try_from(T) -> Result<Self, Self::Err>;
 }
 
+/// The type of a packet.
 #[derive(PartialEq, Eq, Debug)]
 pub enum PacketType {
-    Data,  // packet carries a data payload
-    Fin,   // signals the end of a connection
-    State, // signals acknowledgment of a packet
-    Reset, // forcibly terminates a connection
-    Syn,   // initiates a new connection with a peer
+    /// Carries a data payload
+    Data,
+    /// Signals the end of a connection
+    Fin,
+    /// Signals acknowledgment of a packet
+    State,
+    /// Forcibly terminates a connection
+    Reset,
+    /// Initiates a new connection with a peer
+    Syn,
 }
 
 impl TryFrom<u8> for PacketType {
@@ -74,10 +185,14 @@ impl From<PacketType> for u8 {
     }
 }
 
+/// The type of a packet extension.
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum ExtensionType {
+    /// Marks the end of the extension list
     None,
+    /// Selective acknowledgement of the packets following the acknowledged one
     SelectiveAck,
+    /// Any other extension, which is skipped over
     Unknown(u8),
 }
 
@@ -101,27 +216,36 @@ impl From<ExtensionType> for u8 {
     }
 }
 
+/// A packet extension, borrowed from the packet's bytes.
 #[derive(Clone)]
 pub struct Extension<'a> {
     ty: ExtensionType,
+
+    /// The extension's data, without its type and length
     pub data: &'a [u8],
 }
 
 impl<'a> Extension<'a> {
+    /// Returns the length of the extension's data, in bytes.
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// Returns the extension's type.
     pub fn get_type(&self) -> ExtensionType {
         self.ty
     }
 
+    /// Returns an iterator over the bits of the extension's data.
+    ///
+    /// For a selective acknowledgement, bit `i` tells whether the packet with sequence number
+    /// `ack_nr + 2 + i` was received.
     pub fn iter(&self) -> BitIterator {
         BitIterator::from_bytes(self.data)
     }
 }
 
-#[repr(C)]
+/// A decoded packet header.
 struct PacketHeader {
     type_ver: u8, // type: u4, ver: u4
     extension: u8,
@@ -155,20 +279,24 @@ impl PacketHeader {
     pub fn get_extension_type(&self) -> ExtensionType {
         self.extension.into()
     }
-}
 
-impl AsRef<[u8]> for PacketHeader {
-    /// Returns the packet header as a slice of bytes.
-    fn as_ref(&self) -> &[u8] {
-        unsafe { &*(self as *const PacketHeader as *const [u8; HEADER_SIZE]) }
+    /// Encodes the header into the first `HEADER_SIZE` bytes of `buf`.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        buf[TYPE_VER_OFFSET] = self.type_ver;
+        buf[EXTENSION_OFFSET] = self.extension;
+        write_u16(buf, CONNECTION_ID_OFFSET, self.connection_id);
+        write_u32(buf, TIMESTAMP_OFFSET, self.timestamp);
+        write_u32(buf, TIMESTAMP_DIFFERENCE_OFFSET, self.timestamp_difference);
+        write_u32(buf, WND_SIZE_OFFSET, self.wnd_size);
+        write_u16(buf, SEQ_NR_OFFSET, self.seq_nr);
+        write_u16(buf, ACK_NR_OFFSET, self.ack_nr);
     }
 }
 
 impl<'a> TryFrom<&'a [u8]> for PacketHeader {
     type Err = ParseError;
     /// Reads a byte buffer and returns the corresponding packet header.
-    /// It assumes the fields are in network (big-endian) byte order,
-    /// preserving it.
+    /// It assumes the fields are in network (big-endian) byte order.
     fn try_from(buf: &[u8]) -> Result<Self, Self::Err> {
         // Check length
         if buf.len() < HEADER_SIZE {
@@ -186,14 +314,14 @@ impl<'a> TryFrom<&'a [u8]> for PacketHeader {
         }
 
         Ok(PacketHeader {
-            type_ver: buf[0],
-            extension: buf[1],
-            connection_id: u8_to_unsigned_be!(buf, 2, 3, u16),
-            timestamp: u8_to_unsigned_be!(buf, 4, 7, u32),
-            timestamp_difference: u8_to_unsigned_be!(buf, 8, 11, u32),
-            wnd_size: u8_to_unsigned_be!(buf, 12, 15, u32),
-            seq_nr: u8_to_unsigned_be!(buf, 16, 17, u16),
-            ack_nr: u8_to_unsigned_be!(buf, 18, 19, u16),
+            type_ver: buf[TYPE_VER_OFFSET],
+            extension: buf[EXTENSION_OFFSET],
+            connection_id: read_u16(buf, CONNECTION_ID_OFFSET),
+            timestamp: read_u32(buf, TIMESTAMP_OFFSET),
+            timestamp_difference: read_u32(buf, TIMESTAMP_DIFFERENCE_OFFSET),
+            wnd_size: read_u32(buf, WND_SIZE_OFFSET),
+            seq_nr: read_u16(buf, SEQ_NR_OFFSET),
+            ack_nr: read_u16(buf, ACK_NR_OFFSET),
         })
     }
 }
@@ -213,6 +341,7 @@ impl Default for PacketHeader {
     }
 }
 
+/// An owned packet, stored as its encoded bytes.
 pub struct Packet(Vec<u8>);
 
 impl AsRef<[u8]> for Packet {
@@ -224,96 +353,62 @@ impl AsRef<[u8]> for Packet {
 impl Packet {
     /// Constructs a new, empty packet.
     pub fn new() -> Packet {
-        Packet(PacketHeader::default().as_ref().to_owned())
+        let mut inner = vec![0; HEADER_SIZE];
+        PacketHeader::default().write_to(&mut inner);
+        Packet(inner)
     }
 
     /// Constructs a new data packet with the given payload.
     pub fn with_payload(payload: &[u8]) -> Packet {
-        let mut inner = Vec::with_capacity(HEADER_SIZE + payload.len());
+        Packet::with_payload_in(Vec::with_capacity(HEADER_SIZE + payload.len()), payload)
+    }
+
+    /// Constructs a new data packet with the given payload, reusing `buffer`'s allocation.
+    pub fn with_payload_in(mut buffer: Vec<u8>, payload: &[u8]) -> Packet {
         let mut header = PacketHeader::default();
         header.set_type(PacketType::Data);
-        // inner.copy_from_slice(header.as_ref());
-        // inner.copy_from_slice(payload);
-        inner.extend_from_slice(header.as_ref());
-        inner.extend_from_slice(payload);
+        buffer.clear();
+        buffer.resize(HEADER_SIZE, 0);
+        header.write_to(&mut buffer);
+        buffer.extend_from_slice(payload);
 
-        Packet(inner)
+        Packet(buffer)
     }
 
-    #[inline]
-    pub fn set_type(&mut self, t: PacketType) {
-        let mut header = unsafe { &mut *(self.0.as_mut_ptr() as *mut PacketHeader) };
-        header.set_type(t);
+    /// Returns a borrowed view of the packet.
+    pub fn as_packet_ref(&self) -> PacketRef<'_> {
+        PacketRef(&self.0)
     }
 
-    #[inline]
-    pub fn get_type(&self) -> PacketType {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        header.get_type()
+    /// Consumes the packet, returning the underlying buffer for reuse.
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.0
     }
 
-    pub fn get_version(&self) -> u8 {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        header.get_version()
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.0
     }
 
-    pub fn get_extension_type(&self) -> ExtensionType {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        header.get_extension_type()
+    /// Sets the packet's type.
+    #[inline]
+    pub fn set_type(&mut self, t: PacketType) {
+        let version = 0x0F & self.0[TYPE_VER_OFFSET];
+        self.0[TYPE_VER_OFFSET] = u8::from(t) << 4 | version;
     }
 
+    make_header_getters!();
+    make_header_setters!();
+
+    /// Returns an iterator over the packet's extensions.
     pub fn extensions(&self) -> ExtensionIterator {
-        ExtensionIterator::new(self)
+        ExtensionIterator::new(&self.0)
     }
 
+    /// Returns the packet's payload.
     pub fn payload(&self) -> &[u8] {
-        let mut index = HEADER_SIZE;
-        let mut extension_type = ExtensionType::from(self.0[1]);
-
-        // Consume known extensions and skip over unknown ones
-        while index < self.0.len() && extension_type != ExtensionType::None {
-            let len = self.0[index + 1] as usize;
-
-            // Assume extension is valid because the bytes come from a (valid) Packet
-            // ...
-
-            extension_type = ExtensionType::from(self.0[index]);
-            index += len + 2;
-        }
-
-        &self.0[index..]
-    }
-
-    pub fn timestamp(&self) -> Timestamp {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        u32::from_be(header.timestamp).into()
-    }
-
-    pub fn set_timestamp(&mut self, timestamp: Timestamp) {
-        let header = unsafe { &mut *(self.0.as_mut_ptr() as *mut PacketHeader) };
-        header.timestamp = u32::from(timestamp).to_be();
+        payload(&self.0)
     }
 
-    pub fn timestamp_difference(&self) -> Delay {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        u32::from_be(header.timestamp_difference).into()
-    }
-
-    pub fn set_timestamp_difference(&mut self, delay: Delay) {
-        let header = unsafe { &mut *(self.0.as_mut_ptr() as *mut PacketHeader) };
-        header.timestamp_difference = u32::from(delay).to_be();
-    }
-
-    make_getter!(seq_nr, u16, u16);
-    make_getter!(ack_nr, u16, u16);
-    make_getter!(connection_id, u16, u16);
-    make_getter!(wnd_size, u32, u32);
-
-    make_setter!(set_seq_nr, seq_nr, u16);
-    make_setter!(set_ack_nr, ack_nr, u16);
-    make_setter!(set_connection_id, connection_id, u16);
-    make_setter!(set_wnd_size, wnd_size, u32);
-
     /// Sets Selective ACK field in packet header and adds appropriate data.
     ///
     /// The length of the SACK extension is expressed in bytes, which
@@ -323,14 +418,16 @@ impl Packet {
         // must be a multiple of 4 and at least 4.
         assert!(bv.len() >= 4);
         assert_eq!(bv.len() % 4, 0);
+        // The extension's length is a single byte
+        assert!(bv.len() <= u8::MAX as usize);
 
         let mut index = HEADER_SIZE;
-        let mut extension_type = ExtensionType::from(self.0[1]);
+        let mut extension_type = ExtensionType::from(self.0[EXTENSION_OFFSET]);
 
         // Set extension type in header if none is used, otherwise find and update the
         // "next extension type" marker in the last extension before payload
         if extension_type == ExtensionType::None {
-            self.0[1] = ExtensionType::SelectiveAck.into();
+            self.0[EXTENSION_OFFSET] = ExtensionType::SelectiveAck.into();
         } else {
             // Skip over all extensions until last, then modify its "next extension type" field and
             // add the new extension after it.
@@ -352,21 +449,14 @@ impl Packet {
             }
         }
 
-        // Insert the new extension into the packet's data.
-        // The way this is currently done is potentially slower than the alternative of resizing the
-        // underlying Vec, moving the payload forward and then writing the extension in the "new"
-        // place before the payload.
-
-        // Set the type of the following (non-existent) extension
-        self.0.insert(index, ExtensionType::None.into());
-        // Set this extension's length
-        self.0.insert(index + 1, bv.len() as u8);
-        // Write this extension's data
-        for (i, &value) in bv.iter().enumerate() {
-            self.0.insert(index + 2 + i, value);
-        }
+        // Insert the new extension into the packet's data, moving the payload forward once: the
+        // type of the following (non-existent) extension, this extension's length, and its data
+        let extension_header = [ExtensionType::None.into(), bv.len() as u8];
+        let extension = extension_header.iter().chain(bv.iter()).cloned();
+        self.0.splice(index..index, extension);
     }
 
+    /// Returns the packet's length in bytes, including the header and extensions.
     pub fn len(&self) -> usize {
         self.0.len()
     }
@@ -409,6 +499,203 @@ impl fmt::Debug for Packet {
     }
 }
 
+/// A packet borrowed from a byte slice, such as the buffer a datagram was received into.
+///
+/// Decoding a `PacketRef` validates the packet in place, without copying or allocating anything.
+#[derive(Clone, Copy)]
+pub struct PacketRef<'a>(&'a [u8]);
+
+impl<'a> AsRef<[u8]> for PacketRef<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    make_header_getters!();
+
+    /// Returns an iterator over the packet's extensions.
+    pub fn extensions(&self) -> ExtensionIterator<'a> {
+        ExtensionIterator::new(self.0)
+    }
+
+    /// Returns the packet's payload.
+    pub fn payload(&self) -> &'a [u8] {
+        payload(self.0)
+    }
+
+    /// Returns the packet's length in bytes, including the header and extensions.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether the packet is empty, which a decoded packet never is.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Copies the packet into an owned `Packet`, reusing `buffer`'s allocation.
+    pub fn to_packet_in(self, mut buffer: Vec<u8>) -> Packet {
+        buffer.clear();
+        buffer.extend_from_slice(self.0);
+        Packet(buffer)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for PacketRef<'a> {
+    type Err = ParseError;
+
+    /// Validates a byte slice and borrows it as a packet.
+    fn try_from(buf: &'a [u8]) -> Result<Self, Self::Err> {
+        PacketHeader::try_from(buf)
+            .and(check_extensions(buf))
+            .and(Ok(PacketRef(buf)))
+    }
+}
+
+impl<'a> fmt::Debug for PacketRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacketRef")
+         .field("type", &self.get_type())
+         .field("version", &self.get_version())
+         .field("extension", &self.get_extension_type())
+         .field("connection_id", &self.connection_id())
+         .field("timestamp", &self.timestamp())
+         .field("timestamp_difference", &self.timestamp_difference())
+         .field("wnd_size", &self.wnd_size())
+         .field("seq_nr", &self.seq_nr())
+         .field("ack_nr", &self.ack_nr())
+         .finish()
+    }
+}
+
+/// Encodes a packet directly into a caller-provided buffer, without allocating.
+///
+/// The header is written on creation, with every field but the type and version zeroed.
+/// Extensions have to be added before the payload.
+pub struct PacketEncoder<'a> {
+    buf: &'a mut [u8],
+
+    /// Number of bytes written so far
+    len: usize,
+
+    /// Index of the byte holding the type of the extension following the last one written
+    next_extension: usize,
+
+    /// Whether the payload was already written
+    has_payload: bool,
+}
+
+impl<'a> PacketEncoder<'a> {
+    /// Starts encoding a packet of type `t` into `buf`.
+    ///
+    /// Panics if `buf` can't hold a packet header.
+    pub fn new(buf: &'a mut [u8], t: PacketType) -> PacketEncoder<'a> {
+        let mut header = PacketHeader::default();
+        header.set_type(t);
+        header.write_to(buf);
+
+        PacketEncoder {
+            buf,
+            len: HEADER_SIZE,
+            next_extension: EXTENSION_OFFSET,
+            has_payload: false,
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    make_header_setters!();
+
+    /// Appends a Selective ACK extension with the given data.
+    ///
+    /// As with `Packet::set_sack`, the data's length must be a multiple of 4, at least 4 and at
+    /// most 255. Panics if the payload was already written or if the buffer is too small.
+    pub fn set_sack(&mut self, bv: &[u8]) {
+        assert!(bv.len() >= 4);
+        assert_eq!(bv.len() % 4, 0);
+        assert!(bv.len() <= u8::MAX as usize);
+        assert!(!self.has_payload, "extensions must precede the payload");
+
+        let start = self.len;
+        self.buf[self.next_extension] = ExtensionType::SelectiveAck.into();
+        self.buf[start] = ExtensionType::None.into();
+        self.buf[start + 1] = bv.len() as u8;
+        self.buf[start + 2..start + 2 + bv.len()].copy_from_slice(bv);
+
+        self.next_extension = start;
+        self.len = start + 2 + bv.len();
+    }
+
+    /// Writes the payload. Panics if the buffer is too small.
+    pub fn set_payload(&mut self, payload: &[u8]) {
+        assert!(!self.has_payload, "the payload was already written");
+        self.buf[self.len..self.len + payload.len()].copy_from_slice(payload);
+        self.len += payload.len();
+        self.has_payload = true;
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether nothing was written so far, which never happens as the header comes first.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Finishes encoding, returning the encoded packet.
+    pub fn finish(self) -> PacketRef<'a> {
+        let PacketEncoder { buf, len, .. } = self;
+        PacketRef(&buf[..len])
+    }
+}
+
+/// Reads a big-endian `u16` at `offset`.
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    (buf[offset] as u16) << 8 | buf[offset + 1] as u16
+}
+
+/// Reads a big-endian `u32` at `offset`.
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    (read_u16(buf, offset) as u32) << 16 | read_u16(buf, offset + 2) as u32
+}
+
+/// Writes `value` at `offset` in big-endian byte order.
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = (value >> 8) as u8;
+    buf[offset + 1] = value as u8;
+}
+
+/// Writes `value` at `offset` in big-endian byte order.
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    write_u16(buf, offset, (value >> 16) as u16);
+    write_u16(buf, offset + 2, value as u16);
+}
+
+/// Returns the payload of a valid packet's bytes, skipping over every extension.
+fn payload(data: &[u8]) -> &[u8] {
+    let mut index = HEADER_SIZE;
+    let mut extension_type = ExtensionType::from(data[EXTENSION_OFFSET]);
+
+    // Consume known extensions and skip over unknown ones
+    while index < data.len() && extension_type != ExtensionType::None {
+        let len = data[index + 1] as usize;
+
+        // Assume extension is valid because the bytes come from a (valid) packet
+        // ...
+
+        extension_type = ExtensionType::from(data[index]);
+        index += len + 2;
+    }
+
+    &data[index..]
+}
+
+/// Iterator over the extensions of a packet.
 pub struct ExtensionIterator<'a> {
     raw_bytes: &'a [u8],
     next_extension: ExtensionType,
@@ -416,10 +703,10 @@ pub struct ExtensionIterator<'a> {
 }
 
 impl<'a> ExtensionIterator<'a> {
-    fn new(packet: &'a Packet) -> Self {
+    fn new(raw_bytes: &'a [u8]) -> Self {
         ExtensionIterator {
-            raw_bytes: packet.as_ref(),
-            next_extension: ExtensionType::from(packet.as_ref()[1]),
+            raw_bytes,
+            next_extension: ExtensionType::from(raw_bytes[EXTENSION_OFFSET]),
             index: HEADER_SIZE,
         }
     }
@@ -459,7 +746,7 @@ fn check_extensions(data: &[u8]) -> Result<(), ParseError> {
     }
 
     let mut index = HEADER_SIZE;
-    let mut extension_type = ExtensionType::from(data[1]);
+    let mut extension_type = ExtensionType::from(data[EXTENSION_OFFSET]);
 
     if data.len() == HEADER_SIZE && extension_type != ExtensionType::None {
         return Err(ParseError::InvalidExtensionLength);
@@ -710,6 +997,80 @@ mod tests {
         assert_eq!(packet.as_ref(), buf);
     }
 
+    #[test]
+    fn test_packet_ref_decode() {
+        let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
+                   0x00, 0x04, 0x01, 0x02, 0x03, 0x04, 0x48, 0x69];
+        let packet = PacketRef::try_from(&buf[..]).unwrap();
+        let owned = Packet::try_from(&buf).unwrap();
+        assert_eq!(packet.get_version(), owned.get_version());
+        assert_eq!(packet.get_type(), owned.get_type());
+        assert_eq!(packet.get_extension_type(), ExtensionType::SelectiveAck);
+        assert_eq!(packet.connection_id(), owned.connection_id());
+        assert_eq!(packet.timestamp(), owned.timestamp());
+        assert_eq!(packet.timestamp_difference(), owned.timestamp_difference());
+        assert_eq!(packet.wnd_size(), 1500);
+        assert_eq!(packet.seq_nr(), 43859);
+        assert_eq!(packet.ack_nr(), 15093);
+        assert_eq!(packet.payload(), b"Hi");
+        let extensions: Vec<Extension> = packet.extensions().collect();
+        assert_eq!(extensions.len(), 1);
+        assert_eq!(extensions[0].data, &[1, 2, 3, 4]);
+
+        // Reversible, and the copy reuses the given buffer
+        let buffer = Vec::with_capacity(64);
+        let ptr = buffer.as_ptr();
+        let copy = packet.to_packet_in(buffer);
+        assert_eq!(copy.as_ref(), &buf[..]);
+        let buffer = copy.into_buffer();
+        assert_eq!(buffer.as_ptr(), ptr);
+
+        assert!(PacketRef::try_from(&buf[..HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_packet_encoder() {
+        let mut packet = Packet::with_payload(b"Hello\n");
+        packet.set_type(State);
+        packet.set_timestamp(Timestamp(15270793));
+        packet.set_timestamp_difference(Delay(1707040186));
+        packet.set_connection_id(16808);
+        packet.set_seq_nr(15090);
+        packet.set_ack_nr(17096);
+        packet.set_wnd_size(1048576);
+        packet.set_sack(vec![1, 2, 3, 4]);
+        packet.set_sack(vec![5, 6, 7, 8]);
+
+        // Encoding into a dirty buffer yields the same bytes
+        let mut buf = [0xff; 64];
+        let mut encoder = PacketEncoder::new(&mut buf, State);
+        encoder.set_timestamp(Timestamp(15270793));
+        encoder.set_timestamp_difference(Delay(1707040186));
+        encoder.set_connection_id(16808);
+        encoder.set_seq_nr(15090);
+        encoder.set_ack_nr(17096);
+        encoder.set_wnd_size(1048576);
+        encoder.set_sack(&[1, 2, 3, 4]);
+        encoder.set_sack(&[5, 6, 7, 8]);
+        encoder.set_payload(b"Hello\n");
+        assert_eq!(encoder.len(), packet.len());
+        assert_eq!(encoder.finish().as_ref(), packet.as_ref());
+    }
+
+    #[test]
+    fn test_packet_with_payload_in_reuses_buffer() {
+        let buffer = vec![0xff; 64];
+        let ptr = buffer.as_ptr();
+        let packet = Packet::with_payload_in(buffer, &[1, 2, 3]);
+        assert_eq!(packet.len(), HEADER_SIZE + 3);
+        assert_eq!(packet.get_type(), Data);
+        assert_eq!(packet.seq_nr(), 0);
+        assert_eq!(packet.payload(), &[1, 2, 3]);
+        let buffer = packet.into_buffer();
+        assert_eq!(buffer.as_ptr(), ptr);
+    }
+
     #[test]
     fn test_reversible() {
         let buf = [0x01, 0x00, 0x41, 0xa8, 0x00, 0xe9, 0x03, 0x89,
@@ -749,6 +1110,78 @@ mod tests {
         QuickCheck::new().tests(10000).quickcheck(run as fn(Vec<u8>) -> TestResult)
     }
 
+    // Use quickcheck to compare the header codec against a straightforward big-endian encoding
+    #[test]
+    fn quicktest_header_encoding() {
+        fn run(ty: u8, connection_id: u16, timestamp: u32, timestamp_difference: u32,
+               wnd_size: u32, seq_nr: u16, ack_nr: u16, payload: Vec<u8>) -> bool {
+            let ty = ty % 5;
+            let mut packet = Packet::with_payload(&payload);
+            packet.set_type(PacketType::try_from(ty).unwrap());
+            packet.set_connection_id(connection_id);
+            packet.set_timestamp(Timestamp(timestamp));
+            packet.set_timestamp_difference(Delay::from(timestamp_difference));
+            packet.set_wnd_size(wnd_size);
+            packet.set_seq_nr(seq_nr);
+            packet.set_ack_nr(ack_nr);
+
+            let mut expected = vec![ty << 4 | 1, 0];
+            expected.extend_from_slice(&connection_id.to_be_bytes());
+            expected.extend_from_slice(&timestamp.to_be_bytes());
+            expected.extend_from_slice(&timestamp_difference.to_be_bytes());
+            expected.extend_from_slice(&wnd_size.to_be_bytes());
+            expected.extend_from_slice(&seq_nr.to_be_bytes());
+            expected.extend_from_slice(&ack_nr.to_be_bytes());
+            expected.extend_from_slice(&payload);
+
+            let decoded = PacketRef::try_from(&expected[..]).unwrap();
+            packet.as_ref() == &expected[..] &&
+                u8::from(decoded.get_type()) == ty &&
+                decoded.connection_id() == connection_id &&
+                decoded.timestamp() == Timestamp(timestamp) &&
+                decoded.timestamp_difference() == Delay::from(timestamp_difference) &&
+                decoded.wnd_size() == wnd_size &&
+                decoded.seq_nr() == seq_nr &&
+                decoded.ack_nr() == ack_nr &&
+                decoded.payload() == &payload[..]
+        }
+        QuickCheck::new().tests(10000).quickcheck(run as fn(u8, u16, u32, u32, u32, u16, u16,
+                                                             Vec<u8>) -> bool)
+    }
+
+    // Use quickcheck to check that decoding and re-encoding any valid header is lossless
+    #[test]
+    fn quicktest_header_roundtrip() {
+        fn run(ty: u8, extension: u8, connection_id: u16, timestamp: u32,
+               timestamp_difference: u32, wnd_size: u32, seq_nr: u16, ack_nr: u16) -> bool {
+            // Only the type and version are validated, so build a valid first byte
+            let ty = ty % 5;
+            let mut x = vec![ty << 4 | 1, extension];
+            x.extend_from_slice(&connection_id.to_be_bytes());
+            x.extend_from_slice(&timestamp.to_be_bytes());
+            x.extend_from_slice(&timestamp_difference.to_be_bytes());
+            x.extend_from_slice(&wnd_size.to_be_bytes());
+            x.extend_from_slice(&seq_nr.to_be_bytes());
+            x.extend_from_slice(&ack_nr.to_be_bytes());
+
+            let header = PacketHeader::try_from(&x).unwrap();
+            let mut buf = [0; HEADER_SIZE];
+            header.write_to(&mut buf);
+            &buf[..] == &x[..] &&
+                u8::from(header.get_type()) == ty &&
+                header.get_version() == 1 &&
+                header.extension == extension &&
+                header.connection_id == connection_id &&
+                header.timestamp == timestamp &&
+                header.timestamp_difference == timestamp_difference &&
+                header.wnd_size == wnd_size &&
+                header.seq_nr == seq_nr &&
+                header.ack_nr == ack_nr
+        }
+        QuickCheck::new().tests(10000).quickcheck(run as fn(u8, u8, u16, u32, u32, u32, u16,
+                                                             u16) -> bool)
+    }
+
     #[test]
     fn extension_iterator() {
         let buf = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
@@ -0,0 +1,181 @@
+use std::cmp::{min, max};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Smallest burst the bucket allows, so that a full-sized packet always fits
+const MIN_BURST: u64 = 1500;
+
+/// A token bucket limiting the rate at which bytes flow through one or more sockets.
+///
+/// Cloning a `RateLimiter` yields a new handle to the *same* bucket, which makes it possible to
+/// cap the aggregate bandwidth of many sockets (and the sockets accepted by a `UtpListener`) by
+/// handing each of them a clone of a single limiter.
+///
+/// A rate of zero bytes per second means the limiter doesn't restrict anything.
+///
+/// # Examples
+///
+/// ```no_run
+/// use utp::{RateLimiter, UtpSocket};
+///
+/// // Limit the total upload rate of both sockets to 100 KiB/s
+/// let limiter = RateLimiter::new(100 * 1024);
+///
+/// let mut first = UtpSocket::connect("127.0.0.1:8080").expect("Error connecting");
+/// let mut second = UtpSocket::connect("127.0.0.1:8081").expect("Error connecting");
+/// first.set_rate_limiter(Some(limiter.clone()));
+/// second.set_rate_limiter(Some(limiter.clone()));
+///
+/// // The limit can be changed at any time and takes effect on every socket
+/// limiter.set_rate(50 * 1024);
+/// ```
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// Refill rate in bytes per second
+    rate: u64,
+
+    /// Maximum amount of tokens the bucket may hold
+    burst: u64,
+
+    /// Available tokens; negative when the bucket is in debt
+    tokens: f64,
+
+    /// Last time the bucket was refilled
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// Adds the tokens accumulated since the last refill, up to the bucket's burst size.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.last_refill = now;
+    }
+}
+
+/// Returns the burst size for a given rate: a tenth of a second worth of data, but never less
+/// than a full packet.
+fn burst_for(rate: u64) -> u64 {
+    max(rate / 10, MIN_BURST)
+}
+
+impl RateLimiter {
+    /// Creates a new limiter allowing `bytes_per_second` bytes per second.
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        let burst = burst_for(bytes_per_second);
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: bytes_per_second,
+                burst,
+                tokens: burst as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Returns the current limit in bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the limit to `bytes_per_second` bytes per second.
+    ///
+    /// The new rate applies to every socket sharing this limiter.
+    pub fn set_rate(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = bytes_per_second;
+        bucket.burst = burst_for(bytes_per_second);
+        bucket.tokens = bucket.tokens.min(bucket.burst as f64);
+    }
+
+    /// Takes `bytes` tokens from the bucket and returns how long the caller should wait before
+    /// transferring them.
+    ///
+    /// The bucket may go into debt, in which case later callers will have to wait longer. This
+    /// keeps the aggregate rate of every caller under the limit.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return Duration::from_secs(0);
+        }
+
+        bucket.refill(Instant::now());
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            let wait = -bucket.tokens / bucket.rate as f64;
+            Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+        }
+    }
+
+    /// Returns the number of bytes that can be transferred right now without waiting, capped at
+    /// `limit`.
+    pub fn available(&self, limit: u64) -> u64 {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return limit;
+        }
+
+        bucket.refill(Instant::now());
+        min(bucket.tokens.max(0.0) as u64, limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use rate_limit::RateLimiter;
+
+    #[test]
+    fn test_unlimited_rate() {
+        let limiter = RateLimiter::new(0);
+        for _ in 0..100 {
+            assert_eq!(limiter.reserve(1 << 20), Duration::from_secs(0));
+        }
+        assert_eq!(limiter.available(1234), 1234);
+    }
+
+    #[test]
+    fn test_reserve_within_burst() {
+        let limiter = RateLimiter::new(100_000);
+        assert_eq!(limiter.reserve(1000), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_reserve_over_limit() {
+        // 10 KB/s with a 1500 bytes burst: a 11500 bytes reservation leaves 10000 bytes of debt
+        let limiter = RateLimiter::new(10_000);
+        let wait = limiter.reserve(11_500);
+        assert!(wait > Duration::from_millis(900));
+        assert!(wait <= Duration::from_millis(1000));
+        assert_eq!(limiter.available(::std::u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_shared_bucket() {
+        let limiter = RateLimiter::new(10_000);
+        let clone = limiter.clone();
+        assert_eq!(limiter.reserve(1500), Duration::from_secs(0));
+        // The clone draws from the same, now empty, bucket
+        assert!(clone.reserve(1000) > Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let limiter = RateLimiter::new(10_000);
+        let clone = limiter.clone();
+        clone.set_rate(20_000);
+        assert_eq!(limiter.rate(), 20_000);
+
+        limiter.set_rate(0);
+        assert_eq!(clone.reserve(1 << 20), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+
+/// Fixed-capacity ring buffer of items indexed by (wrapping) 16-bit sequence numbers.
+///
+/// The buffer covers the sequence numbers from `first_seq_nr()` to
+/// `first_seq_nr() + capacity() - 1`, each of which maps to a single slot. Inserting, looking up,
+/// removing and advancing past items are all constant-time operations, and memory usage never
+/// exceeds `capacity()` items.
+pub struct SequenceBuffer<T> {
+    slots: Vec<Option<T>>,
+
+    /// Sequence number of the slot at `head`
+    first: u16,
+
+    /// Index of the slot holding the item with sequence number `first`
+    head: usize,
+
+    /// Number of occupied slots
+    len: usize,
+}
+
+impl<T> SequenceBuffer<T> {
+    /// Creates an empty buffer holding at most `capacity` items, starting at sequence number 0.
+    ///
+    /// The capacity must not exceed half the sequence number space, or wrapping sequence numbers
+    /// would become ambiguous.
+    pub fn with_capacity(capacity: usize) -> SequenceBuffer<T> {
+        assert!(capacity > 0 && capacity <= 1 << 15);
+        SequenceBuffer {
+            slots: (0..capacity).map(|_| None).collect(),
+            first: 0,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the maximum number of items the buffer can hold.
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns the number of items in the buffer.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the buffer holds no items.
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the lowest sequence number the buffer can hold.
+    #[cfg(test)]
+    pub fn first_seq_nr(&self) -> u16 {
+        self.first
+    }
+
+    /// Removes every item and makes `first` the lowest sequence number the buffer can hold.
+    pub fn reset(&mut self, first: u16) {
+        for slot in &mut self.slots {
+            *slot = None;
+        }
+        self.first = first;
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Returns the slot index for a sequence number, if it falls within the buffer's range.
+    fn index(&self, seq_nr: u16) -> Option<usize> {
+        let offset = seq_nr.wrapping_sub(self.first) as usize;
+        if offset < self.capacity() {
+            Some((self.head + offset) % self.capacity())
+        } else {
+            None
+        }
+    }
+
+    /// Inserts an item with the given sequence number.
+    ///
+    /// Hands the item back, leaving the buffer untouched, if the sequence number is out of the
+    /// buffer's range or an item with the same sequence number is already present.
+    pub fn insert(&mut self, seq_nr: u16, item: T) -> Result<(), T> {
+        match self.index(seq_nr) {
+            Some(index) if self.slots[index].is_none() => {
+                self.slots[index] = Some(item);
+                self.len += 1;
+                Ok(())
+            }
+            _ => Err(item),
+        }
+    }
+
+    /// Returns whether an item with the given sequence number is present.
+    pub fn contains(&self, seq_nr: u16) -> bool {
+        self.get(seq_nr).is_some()
+    }
+
+    /// Returns a reference to the item with the given sequence number, if present.
+    pub fn get(&self, seq_nr: u16) -> Option<&T> {
+        self.index(seq_nr).and_then(|index| self.slots[index].as_ref())
+    }
+
+    /// Returns how many of the sequence numbers following `seq_nr` the buffer can hold.
+    pub fn room_after(&self, seq_nr: u16) -> usize {
+        let offset = seq_nr.wrapping_sub(self.first).wrapping_add(1) as usize;
+        self.capacity().saturating_sub(offset)
+    }
+
+    /// Returns a reference to the item with the lowest sequence number the buffer can hold, if
+    /// present.
+    pub fn front(&self) -> Option<&T> {
+        self.slots[self.head].as_ref()
+    }
+
+    /// Advances the buffer's range by one sequence number, removing and returning the item with
+    /// the lowest sequence number, if present.
+    pub fn pop_front(&mut self) -> Option<T> {
+        let item = self.slots[self.head].take();
+        if item.is_some() {
+            self.len -= 1;
+        }
+        self.head = (self.head + 1) % self.capacity();
+        self.first = self.first.wrapping_add(1);
+        item
+    }
+}
+
+/// Growable queue of items with increasing (wrapping) 16-bit sequence numbers.
+///
+/// Items are appended at the back and usually leave from the front, but they may also be removed
+/// from the middle, in which case their slot stays empty until the front of the queue moves past
+/// it. Looking up and removing items by sequence number are constant-time operations.
+pub struct SequenceQueue<T> {
+    slots: VecDeque<Option<T>>,
+
+    /// Sequence number of the front slot
+    first: u16,
+
+    /// Number of occupied slots
+    len: usize,
+}
+
+impl<T> SequenceQueue<T> {
+    /// Creates an empty queue.
+    pub fn new() -> SequenceQueue<T> {
+        SequenceQueue {
+            slots: VecDeque::new(),
+            first: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of items in the queue.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the queue holds no items.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the sequence number of the front slot, which may be empty, or `None` if the queue
+    /// is empty.
+    pub fn front_seq_nr(&self) -> Option<u16> {
+        if self.slots.is_empty() {
+            None
+        } else {
+            Some(self.first)
+        }
+    }
+
+    /// Returns the slot index for a sequence number, if it falls within the queue.
+    fn index(&self, seq_nr: u16) -> Option<usize> {
+        let offset = seq_nr.wrapping_sub(self.first) as usize;
+        if offset < self.slots.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Appends an item with the given sequence number, leaving empty slots for any sequence
+    /// numbers skipped since the last item.
+    ///
+    /// Panics if the sequence number doesn't follow the one of the last slot.
+    pub fn push_back(&mut self, seq_nr: u16, item: T) {
+        if self.slots.is_empty() {
+            self.first = seq_nr;
+        }
+        let offset = seq_nr.wrapping_sub(self.first) as usize;
+        assert!(offset >= self.slots.len() && offset < 1 << 15,
+                "sequence number {} out of order", seq_nr);
+        while self.slots.len() < offset {
+            self.slots.push_back(None);
+        }
+        self.slots.push_back(Some(item));
+        self.len += 1;
+    }
+
+    /// Returns whether an item with the given sequence number is present.
+    pub fn contains(&self, seq_nr: u16) -> bool {
+        self.get(seq_nr).is_some()
+    }
+
+    /// Returns a reference to the item with the given sequence number, if present.
+    pub fn get(&self, seq_nr: u16) -> Option<&T> {
+        self.index(seq_nr).and_then(|index| self.slots[index].as_ref())
+    }
+
+    /// Returns a mutable reference to the item with the given sequence number, if present.
+    pub fn get_mut(&mut self, seq_nr: u16) -> Option<&mut T> {
+        match self.index(seq_nr) {
+            Some(index) => self.slots[index].as_mut(),
+            None => None,
+        }
+    }
+
+    /// Removes and returns the item with the given sequence number, if present.
+    pub fn remove(&mut self, seq_nr: u16) -> Option<T> {
+        let item = self.index(seq_nr).and_then(|index| self.slots[index].take());
+        if item.is_some() {
+            self.len -= 1;
+            self.clear_if_empty();
+        }
+        item
+    }
+
+    /// Removes the front slot, returning its item, if any.
+    pub fn pop_front(&mut self) -> Option<T> {
+        let item = match self.slots.pop_front() {
+            Some(slot) => {
+                self.first = self.first.wrapping_add(1);
+                slot
+            }
+            None => None,
+        };
+        if item.is_some() {
+            self.len -= 1;
+            self.clear_if_empty();
+        }
+        item
+    }
+
+    /// Drops the remaining empty slots once the last item is gone, so the next item may start a
+    /// new sequence.
+    fn clear_if_empty(&mut self) {
+        if self.len == 0 {
+            self.slots.clear();
+        }
+    }
+
+    /// Returns an iterator over the items, in sequence number order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use seq_buffer::{SequenceBuffer, SequenceQueue};
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut buffer = SequenceBuffer::with_capacity(4);
+        buffer.reset(10);
+        assert!(buffer.is_empty());
+
+        assert!(buffer.insert(11, 'b').is_ok());
+        assert!(buffer.insert(13, 'd').is_ok());
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.get(11), Some(&'b'));
+        assert_eq!(buffer.get(12), None);
+        assert!(buffer.contains(13));
+
+        // Duplicates are rejected
+        assert_eq!(buffer.insert(11, 'x'), Err('x'));
+        assert_eq!(buffer.get(11), Some(&'b'));
+
+        // So are sequence numbers out of range
+        assert_eq!(buffer.insert(9, 'x'), Err('x'));
+        assert_eq!(buffer.insert(14, 'x'), Err('x'));
+        assert_eq!(buffer.len(), 2);
+    }
+
+    #[test]
+    fn test_pop_front() {
+        let mut buffer = SequenceBuffer::with_capacity(4);
+        buffer.reset(10);
+        assert!(buffer.insert(10, 'a').is_ok());
+        assert!(buffer.insert(12, 'c').is_ok());
+
+        assert_eq!(buffer.front(), Some(&'a'));
+        assert_eq!(buffer.pop_front(), Some('a'));
+        assert_eq!(buffer.first_seq_nr(), 11);
+        assert_eq!(buffer.front(), None);
+        assert_eq!(buffer.pop_front(), None);
+        assert_eq!(buffer.pop_front(), Some('c'));
+        assert!(buffer.is_empty());
+
+        // The range moved forward
+        assert!(buffer.insert(16, 'g').is_ok());
+        assert_eq!(buffer.insert(17, 'h'), Err('h'));
+    }
+
+    #[test]
+    fn test_sequence_number_wraparound() {
+        let mut buffer = SequenceBuffer::with_capacity(8);
+        buffer.reset(::std::u16::MAX - 1);
+        for seq_nr in 0..4u16 {
+            assert!(buffer.insert(seq_nr.wrapping_sub(2), seq_nr).is_ok());
+        }
+
+        assert_eq!(buffer.get(::std::u16::MAX), Some(&1));
+        assert_eq!(buffer.get(0), Some(&2));
+        assert_eq!(buffer.len(), 4);
+
+        for expected in 0..4 {
+            assert_eq!(buffer.pop_front(), Some(expected));
+        }
+        assert_eq!(buffer.first_seq_nr(), 2);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_queue_lookup_and_removal() {
+        let mut queue = SequenceQueue::new();
+        assert_eq!(queue.front_seq_nr(), None);
+        for seq_nr in 5..10u16 {
+            queue.push_back(seq_nr, seq_nr * 10);
+        }
+        assert_eq!(queue.len(), 5);
+        assert_eq!(queue.front_seq_nr(), Some(5));
+        assert_eq!(queue.get(7), Some(&70));
+        assert_eq!(queue.get(10), None);
+
+        // Removing from the middle leaves an empty slot behind
+        assert_eq!(queue.remove(6), Some(60));
+        assert!(!queue.contains(6));
+        assert_eq!(queue.iter().cloned().collect::<Vec<_>>(), vec![50, 70, 80, 90]);
+
+        assert_eq!(queue.pop_front(), Some(50));
+        assert_eq!(queue.front_seq_nr(), Some(6));
+        assert_eq!(queue.pop_front(), None);
+        assert_eq!(queue.front_seq_nr(), Some(7));
+
+        if let Some(item) = queue.get_mut(8) {
+            *item += 1;
+        }
+        assert_eq!(queue.get(8), Some(&81));
+    }
+
+    #[test]
+    fn test_queue_wraparound() {
+        let mut queue = SequenceQueue::new();
+        queue.push_back(::std::u16::MAX, 'a');
+        queue.push_back(0, 'b');
+        queue.push_back(2, 'd');
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.get(1), None);
+        assert_eq!(queue.get(2), Some(&'d'));
+
+        // Emptying the queue allows starting over anywhere
+        assert_eq!(queue.remove(::std::u16::MAX), Some('a'));
+        assert_eq!(queue.remove(0), Some('b'));
+        assert_eq!(queue.remove(2), Some('d'));
+        assert!(queue.is_empty());
+        assert_eq!(queue.front_seq_nr(), None);
+        queue.push_back(100, 'x');
+        assert_eq!(queue.front_seq_nr(), Some(100));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_queue_out_of_order_push() {
+        let mut queue = SequenceQueue::new();
+        queue.push_back(10, ());
+        queue.push_back(9, ());
+    }
+}
@@ -1,13 +1,27 @@
 use std::cmp::{min, max};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::net::{ToSocketAddrs, SocketAddr, UdpSocket};
 use std::io::{Result, ErrorKind};
+use std::mem;
 use util::*;
 use packet::*;
-use error::SocketError;
+use bit_iterator::Bitfield;
+use buffer_pool::BufferPool;
+use capture::PacketCapture;
+use metrics::{ConnectionMetrics, Counters, ListenerMetrics, listener_metrics};
+use observer::{DropReason, PacketObserver};
+use seq_buffer::{SequenceBuffer, SequenceQueue};
+use stats::SocketStats;
+use syn_cookie::CookieJar;
+use error::UtpError;
+use events::{CloseReason, EventHandler};
 use rand;
+use rate_limit::RateLimiter;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 use time::*;
+use time_wait::{ClosedConnection, TimeWait};
 
 // For simplicity's sake, let us assume no packet will ever exceed the
 // Ethernet maximum transfer unit of 1500 bytes.
@@ -24,7 +38,18 @@ const MAX_CONGESTION_TIMEOUT: u64 = 60_000; // one minute
 const BASE_HISTORY: usize = 10; // base delays history size
 const MAX_SYN_RETRIES: u32 = 5; // maximum connection retries
 const MAX_RETRANSMISSION_RETRIES: u32 = 5; // maximum retransmission retries
+const DUPLICATE_ACKS_BEFORE_RESEND: u32 = 3; // packets acknowledged past a lost one
+const SACK_LEN: usize = 4; // selective acknowledgement length in bytes, as in libutp
+const INCOMING_BUFFER_SIZE: usize = 1024; // maximum number of packets held for reassembly
 const WINDOW_SIZE: u32 = 1024 * 1024; // local receive window size
+const SEND_BUFFER_SIZE: usize = 1024 * 1024; // default send buffer size
+// Most unsent and unacknowledged packets, a quarter of the sequence space, so that their sequence
+// numbers can always be compared
+const MAX_BUFFERED_PACKETS: usize = 1 << 14;
+const MAX_POOLED_BUFFERS: usize = 64; // packet buffers kept around for reuse
+const HALF_OPEN_TIMEOUT: u64 = 32_000; // longest a client keeps retransmitting its SYN
+const MAX_HALF_OPEN: usize = 1024; // most handshakes a listener remembers at once
+const MAX_COOKIE_REORDERING: u16 = 8; // most packets overtaking the cookie echo of a handshake
 
 // Maximum time (in microseconds) to wait for incoming packets when the send window is full
 const PRE_SEND_TIMEOUT: u32 = 500_000;
@@ -40,6 +65,22 @@ enum SocketState {
     FinSent,
     ResetReceived,
     Closed,
+    Aborted,
+}
+
+impl SocketState {
+    /// Returns the name of the state in metrics.
+    fn label(&self) -> &'static str {
+        match *self {
+            SocketState::New => "new",
+            SocketState::Connected => "connected",
+            SocketState::SynSent => "syn_sent",
+            SocketState::FinSent => "fin_sent",
+            SocketState::ResetReceived => "reset_received",
+            SocketState::Closed => "closed",
+            SocketState::Aborted => "aborted",
+        }
+    }
 }
 
 struct DelayDifferenceSample {
@@ -47,17 +88,40 @@ struct DelayDifferenceSample {
     difference: Delay,
 }
 
+/// A sent but not yet acknowledged packet, along with its retransmission bookkeeping.
+struct SentPacket {
+    packet: Packet,
+
+    /// How many times the packet was sent
+    transmissions: u32,
+
+    /// When the packet was last sent
+    last_sent: Instant,
+}
+
+impl SentPacket {
+    /// Wraps a packet that was just sent for the first time.
+    fn new(packet: Packet) -> SentPacket {
+        SentPacket {
+            packet,
+            transmissions: 1,
+            last_sent: Instant::now(),
+        }
+    }
+}
+
 /// Returns the first valid address in a `ToSocketAddrs` iterator.
 fn take_address<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr> {
     addr.to_socket_addrs()
-        .and_then(|mut it| it.next().ok_or(SocketError::InvalidAddress.into()))
+        .and_then(|mut it| it.next().ok_or(UtpError::InvalidAddress.into()))
 }
 
 /// A structure that represents a uTP (Micro Transport Protocol) connection between a local socket
 /// and a remote socket.
 ///
 /// The socket will be closed when the value is dropped (either explicitly or when it goes out of
-/// scope).
+/// scope). By default the connection is then closed in a background thread, so dropping never
+/// blocks; see `set_linger` for other policies.
 ///
 /// The default maximum retransmission retries is 5, which translates to about 16 seconds. It can be
 /// changed by assigning the desired maximum retransmission retries to a socket's
@@ -101,18 +165,31 @@ pub struct UtpSocket {
     /// Sequence number of the latest acknowledged packet sent by the remote peer
     ack_nr: u16,
 
+    /// Sequence number of the reply to the remote peer's SYN, sent again if the SYN is repeated
+    initial_seq_nr: u16,
+
     /// Socket state
     state: SocketState,
 
-    /// Received but not acknowledged packets
-    incoming_buffer: Vec<Packet>,
+    /// Received but not yet read packets, indexed by sequence number starting from the next
+    /// packet to be read
+    incoming_buffer: SequenceBuffer<Packet>,
 
     /// Sent but not yet acknowledged packets
-    send_window: Vec<Packet>,
+    send_window: SequenceQueue<SentPacket>,
 
     /// Packets not yet sent
     unsent_queue: VecDeque<Packet>,
 
+    /// Buffers of packets that were read or acknowledged, reused for new packets
+    buffers: BufferPool,
+
+    /// Maximum size in bytes of the unsent and unacknowledged packets
+    send_buffer_size: usize,
+
+    /// Whether writes fail instead of waiting for room in the send buffer
+    nonblocking: bool,
+
     /// How many ACKs did the socket receive for packet with sequence number equal to `ack_nr`
     duplicate_ack_count: u32,
 
@@ -122,17 +199,15 @@ pub struct UtpSocket {
     /// Timestamp of the latest packet the remote peer acknowledged
     last_acked_timestamp: Timestamp,
 
-    /// Sequence number of the last packet removed from the incoming buffer
-    last_dropped: u16,
-
     /// Round-trip time to remote peer
     rtt: i32,
 
     /// Variance of the round-trip time to the remote peer
     rtt_variance: i32,
 
-    /// Data from the latest packet not yet returned in `recv_from`
-    pending_data: Vec<u8>,
+    /// How many bytes of the first packet in the incoming buffer were already returned in
+    /// `recv_from`
+    pending_offset: usize,
 
     /// Bytes in flight
     curr_window: u32,
@@ -158,6 +233,52 @@ pub struct UtpSocket {
     /// Congestion window in bytes
     cwnd: u32,
 
+    /// Limiter for outgoing data, possibly shared with other sockets
+    rate_limiter: Option<RateLimiter>,
+
+    /// Limiter for incoming data, possibly shared with other sockets
+    recv_rate_limiter: Option<RateLimiter>,
+
+    /// Sink recording every datagram sent and received, possibly shared with other sockets
+    capture: Option<PacketCapture>,
+
+    /// Hook notified of every packet sent, received or dropped
+    observer: Option<Arc<dyn PacketObserver>>,
+
+    /// Handler notified of connection lifecycle events
+    event_handler: Option<Arc<dyn EventHandler>>,
+
+    /// Traffic and loss counters
+    stats: SocketStats,
+
+    /// How long dropping the socket may wait for a graceful close, or `None` to close it in the
+    /// background
+    linger: Option<Duration>,
+
+    /// Time after which blocking operations give up, while closing with a linger timeout
+    deadline: Option<Instant>,
+
+    /// Closed connections of the listener that accepted this socket, if they wait for late
+    /// packets
+    time_wait: Option<TimeWait>,
+
+    /// Counters of the listener that accepted this socket, if any
+    metrics: Option<ConnectionMetrics>,
+
+    /// Handshakes remembered by the listener that accepted this socket, and the key of this
+    /// socket's, until the remote peer is known to have received the reply
+    handshake: Option<(HalfOpenTable, (SocketAddr, u16))>,
+
+    /// Whether the remote peer may still move to another port, as a listener does after a
+    /// stateless handshake
+    peer_may_move: bool,
+
+    /// Whether to spread outgoing packets evenly over the round-trip time
+    pacing: bool,
+
+    /// When the latest packet was sent, for pacing purposes
+    last_sent: Option<Instant>,
+
     /// Maximum retransmission retries
     pub max_retransmission_retries: u32,
 }
@@ -176,17 +297,20 @@ impl UtpSocket {
             sender_connection_id: sender_id,
             seq_nr: 1,
             ack_nr: 0,
+            initial_seq_nr: 0,
             state: SocketState::New,
-            incoming_buffer: Vec::new(),
-            send_window: Vec::new(),
+            incoming_buffer: SequenceBuffer::with_capacity(INCOMING_BUFFER_SIZE),
+            send_window: SequenceQueue::new(),
             unsent_queue: VecDeque::new(),
+            buffers: BufferPool::new(MAX_POOLED_BUFFERS, BUF_SIZE),
+            send_buffer_size: SEND_BUFFER_SIZE,
+            nonblocking: false,
             duplicate_ack_count: 0,
             last_acked: 0,
             last_acked_timestamp: Timestamp::default(),
-            last_dropped: 0,
             rtt: 0,
             rtt_variance: 0,
-            pending_data: Vec::new(),
+            pending_offset: 0,
             curr_window: 0,
             remote_wnd_size: 0,
             current_delays: Vec::new(),
@@ -195,6 +319,20 @@ impl UtpSocket {
             last_rollover: Timestamp::default(),
             congestion_timeout: INITIAL_CONGESTION_TIMEOUT,
             cwnd: INIT_CWND * MSS,
+            rate_limiter: None,
+            recv_rate_limiter: None,
+            capture: None,
+            observer: None,
+            event_handler: None,
+            stats: SocketStats::default(),
+            metrics: None,
+            handshake: None,
+            linger: None,
+            deadline: None,
+            time_wait: None,
+            peer_may_move: false,
+            pacing: false,
+            last_sent: None,
             max_retransmission_retries: MAX_RETRANSMISSION_RETRIES,
         }
     }
@@ -219,10 +357,112 @@ impl UtpSocket {
         if self.state == SocketState::Connected || self.state == SocketState::FinSent {
             Ok(self.connected_to)
         } else {
-            Err(SocketError::NotConnected.into())
+            Err(UtpError::NotConnected.into())
+        }
+    }
+
+    /// Registers a handler notified of this connection's lifecycle events, such as its
+    /// establishment, packet loss or closing.
+    ///
+    /// Sockets returned by `connect` are already connected, so only sockets accepted by a
+    /// `UtpListener` with a handler report `on_connected`. Passing `None` removes the current
+    /// handler.
+    pub fn set_event_handler(&mut self, handler: Option<Arc<dyn EventHandler>>) {
+        self.event_handler = handler;
+    }
+
+    /// Returns a snapshot of this socket's traffic counters and congestion control state.
+    pub fn stats(&self) -> SocketStats {
+        SocketStats {
+            rtt: Duration::from_millis(max(self.rtt, 0) as u64),
+            rtt_variance: Duration::from_millis(max(self.rtt_variance, 0) as u64),
+            rto: Duration::from_millis(self.congestion_timeout),
+            cwnd: self.cwnd,
+            flight_size: self.curr_window,
+            peer_window: self.remote_wnd_size,
+            queuing_delay: Duration::from_micros(max(self.queuing_delay().0, 0) as u64),
+            ..self.stats.clone()
         }
     }
 
+    /// Limits the rate at which this socket sends data.
+    ///
+    /// The same `RateLimiter` may be shared by several sockets to cap their aggregate upload rate.
+    /// Passing `None` removes any limit.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    /// Limits the rate at which this socket receives data.
+    ///
+    /// The remote peer is slowed down by shrinking the advertised receive window and by delaying
+    /// acknowledgements, so no data is ever dropped. The same `RateLimiter` may be shared by
+    /// several sockets to cap their aggregate download rate. Passing `None` removes any limit.
+    pub fn set_recv_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.recv_rate_limiter = limiter;
+    }
+
+    /// Records every datagram this socket sends and receives into a pcap capture.
+    ///
+    /// The same `PacketCapture` may be shared by several sockets to record all of them in a single
+    /// file. Passing `None` stops recording.
+    pub fn set_capture(&mut self, capture: Option<PacketCapture>) {
+        self.capture = capture;
+    }
+
+    /// Registers an observer notified of every packet this socket sends, receives or drops.
+    ///
+    /// Passing `None` removes the current observer.
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn PacketObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Enables or disables packet pacing.
+    ///
+    /// When enabled, instead of sending as many packets back to back as the congestion window
+    /// allows, the socket spreads them evenly over the smoothed round-trip time. This avoids
+    /// bursts that overflow shallow router buffers and inflate the queuing delay. Pacing is
+    /// disabled by default.
+    pub fn set_pacing(&mut self, enabled: bool) {
+        self.pacing = enabled;
+    }
+
+    /// Sets the size in bytes of the send buffer, which holds the packets written to the socket
+    /// until the remote peer acknowledges them. The size is rounded up to at least one full packet,
+    /// and down to at most 16384 full packets.
+    ///
+    /// Writes accept only as much data as fits in the buffer, returning a short count when it
+    /// fills up. The buffer never holds more than 16384 packets either, however small they are.
+    /// The default size is 1 MiB.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.send_buffer_size = min(max(size, MSS as usize), MAX_BUFFERED_PACKETS * MSS as usize);
+    }
+
+    /// Moves writes into or out of non-blocking mode.
+    ///
+    /// In non-blocking mode, writes never wait for acknowledgements: they send as many packets as
+    /// the congestion window allows, queue the rest, and fail with `WouldBlock` if the send buffer
+    /// is full. Reads, `flush` and `close` still block.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Sets what happens to the connection when the socket is dropped without being closed.
+    ///
+    /// - `None`, the default: dropping returns immediately, and the connection is closed
+    ///   gracefully in a background thread;
+    /// - `Some(timeout)`: dropping closes the connection gracefully, waiting at most `timeout`
+    ///   for the remote peer to acknowledge every packet. If it doesn't, the connection is aborted
+    ///   with a `Reset` packet;
+    /// - `Some(Duration::from_secs(0))`: dropping aborts the connection right away, discarding
+    ///   any unacknowledged data.
+    ///
+    /// Background closes don't keep the process alive, so programs about to exit should call
+    /// `close` explicitly instead.
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
+    }
+
     /// Opens a connection to a remote host by hostname or IP address.
     ///
     /// The address type can be any implementer of the `ToSocketAddr` trait. See its documentation
@@ -243,7 +483,7 @@ impl UtpSocket {
         packet.set_connection_id(socket.receiver_connection_id);
         packet.set_seq_nr(socket.seq_nr);
 
-        let mut len = 0;
+        let mut len = None;
         let mut buf = [0; BUF_SIZE];
 
         let mut syn_timeout = socket.congestion_timeout;
@@ -252,8 +492,9 @@ impl UtpSocket {
 
             // Send packet
             debug!("Connecting to {}", socket.connected_to);
-            try!(socket.socket.send_to(packet.as_ref(), socket.connected_to));
-            socket.state = SocketState::SynSent;
+            let retransmission = socket.state == SocketState::SynSent;
+            try!(socket.send_datagram(packet.as_ref(), socket.connected_to, retransmission));
+            socket.set_state(SocketState::SynSent);
             debug!("sent {:?}", packet);
 
             // Validate response
@@ -262,8 +503,9 @@ impl UtpSocket {
                   .expect("Error setting read timeout");
             match socket.socket.recv_from(&mut buf) {
                 Ok((read, src)) => {
+                    socket.notify_received(&buf[..read], src);
                     socket.connected_to = src;
-                    len = read;
+                    len = Some(read);
                     break;
                 }
                 Err(ref e) if (e.kind() == ErrorKind::WouldBlock ||
@@ -276,9 +518,26 @@ impl UtpSocket {
             };
         }
 
+        let len = match len {
+            Some(len) => len,
+            None => return Err(UtpError::HandshakeTimeout.into()),
+        };
+
         let addr = socket.connected_to;
-        let packet = try!(Packet::try_from(&buf[..len]));
+        let packet = match PacketRef::try_from(&buf[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                socket.notify_dropped(&buf[..len], addr, DropReason::Malformed);
+                return Err(e.into());
+            }
+        };
         debug!("received {:?}", packet);
+
+        // The reply must carry the connection identifier the request was sent with
+        if packet.get_type() == PacketType::State &&
+           packet.connection_id() != socket.receiver_connection_id {
+            return Err(UtpError::ConnectionIdMismatch.into());
+        }
         try!(socket.handle_packet(&packet, addr));
 
         debug!("connected to: {}", socket.connected_to);
@@ -291,6 +550,10 @@ impl UtpSocket {
     /// This method allows both peers to receive all packets still in
     /// flight.
     pub fn close(&mut self) -> Result<()> {
+        if self.state == SocketState::Aborted {
+            return Err(UtpError::ConnectionAborted.into());
+        }
+
         // Nothing to do if the socket's already closed or not connected
         if self.state == SocketState::Closed ||
             self.state == SocketState::New ||
@@ -309,9 +572,9 @@ impl UtpSocket {
         packet.set_type(PacketType::Fin);
 
         // Send FIN
-        try!(self.socket.send_to(packet.as_ref(), self.connected_to));
+        try!(self.send_datagram(packet.as_ref(), self.connected_to, false));
         debug!("sent {:?}", packet);
-        self.state = SocketState::FinSent;
+        self.set_state(SocketState::FinSent);
 
         // Receive JAKE
         let mut buf = [0; BUF_SIZE];
@@ -322,12 +585,83 @@ impl UtpSocket {
         Ok(())
     }
 
+    /// Aborts the connection immediately.
+    ///
+    /// Unlike `close`, this doesn't wait for the remote peer: a `Reset` packet is sent to it, and
+    /// every unsent and unacknowledged packet is discarded. Any later operation on the socket fails
+    /// with `ConnectionAborted`.
+    pub fn abort(&mut self) -> Result<()> {
+        let connected = self.state == SocketState::Connected ||
+                        self.state == SocketState::FinSent;
+        let mut result = Ok(());
+        if connected {
+            let mut packet = Packet::new();
+            packet.set_type(PacketType::Reset);
+            packet.set_connection_id(self.sender_connection_id);
+            packet.set_seq_nr(self.seq_nr);
+            packet.set_ack_nr(self.ack_nr);
+            packet.set_timestamp(now_microseconds());
+            result = self.send_datagram(packet.as_ref(), self.connected_to, false).map(|_| ());
+            debug!("sent {:?}", packet);
+        }
+
+        self.unsent_queue.clear();
+        while !self.send_window.is_empty() {
+            if let Some(sent) = self.send_window.pop_front() {
+                self.buffers.put(sent.packet.into_buffer());
+            }
+        }
+        self.curr_window = 0;
+        self.set_state(SocketState::Aborted);
+        if connected {
+            self.notify_event(|handler, peer| handler.on_closed(peer, CloseReason::Aborted));
+        }
+
+        result
+    }
+
+    /// Closes a connection that is still open when the socket is dropped, according to the
+    /// linger policy.
+    fn close_on_drop(&mut self) {
+        match self.linger {
+            Some(timeout) if timeout == Duration::from_secs(0) => {
+                let _ = self.abort();
+            }
+            Some(timeout) => {
+                self.deadline = Some(Instant::now() + timeout);
+                if self.close().is_err() {
+                    let _ = self.abort();
+                }
+            }
+            None => {
+                // Move the connection to a background thread, leaving a closed socket behind
+                let placeholder = match self.socket.try_clone() {
+                    Ok(socket) => UtpSocket::from_raw_parts(socket, self.connected_to),
+                    Err(_) => {
+                        let _ = self.abort();
+                        return;
+                    }
+                };
+                let mut socket = mem::replace(self, placeholder);
+                thread::spawn(move || {
+                    if socket.close().is_err() {
+                        let _ = socket.abort();
+                    }
+                });
+            }
+        }
+    }
+
     /// Receives data from socket.
     ///
     /// On success, returns the number of bytes read and the sender's address.
     /// Returns 0 bytes read after receiving a FIN packet when the remaining
     /// in-flight packets are consumed.
     pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        if self.state == SocketState::Aborted {
+            return Err(UtpError::ConnectionAborted.into());
+        }
+
         let read = self.flush_incoming_buffer(buf);
 
         if read > 0 {
@@ -336,7 +670,7 @@ impl UtpSocket {
             // If the socket received a reset packet and all data has been flushed, then it can't
             // receive anything else
             if self.state == SocketState::ResetReceived {
-                return Err(SocketError::ConnectionReset.into());
+                return Err(UtpError::ConnectionReset.into());
             }
 
             loop {
@@ -364,15 +698,25 @@ impl UtpSocket {
         loop {
             // Abort loop if the current try exceeds the maximum number of retransmission retries.
             if retries >= self.max_retransmission_retries {
-                self.state = SocketState::Closed;
-                return Err(SocketError::ConnectionTimedOut.into());
+                self.set_state(SocketState::Closed);
+                self.notify_event(|handler, peer| handler.on_closed(peer, CloseReason::TimedOut));
+                return Err(UtpError::RetransmissionTimeout.into());
             }
 
-            let timeout = if self.state != SocketState::New {
+            let mut timeout = if self.state != SocketState::New {
                 debug!("setting read timeout of {} ms", self.congestion_timeout);
                 Some(Duration::from_millis(self.congestion_timeout))
             } else { None };
 
+            // Never wait past the deadline of a lingering close, if any
+            if let Some(deadline) = self.deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                timeout = Some(timeout.map_or(deadline - now, |t| min(t, deadline - now)));
+            }
+
             self.socket.set_read_timeout(timeout).expect("Error setting read timeout");
             match self.socket.recv_from(&mut b) {
                 Ok((r, s)) => { read = r; src = s; break },
@@ -390,29 +734,42 @@ impl UtpSocket {
             retries += 1;
         }
 
+        self.notify_received(&b[..read], src);
+        self.handle_datagram(&b[..read], src, buf)
+    }
+
+    /// Decodes and handles a datagram received from `src`, then flushes the incoming buffer into
+    /// `buf`.
+    fn handle_datagram(&mut self, datagram: &[u8], src: SocketAddr, buf: &mut [u8])
+                       -> Result<(usize, SocketAddr)> {
         // Decode received data into a packet
-        let packet = match Packet::try_from(&b[..read]) {
+        let packet = match PacketRef::try_from(datagram) {
             Ok(packet) => packet,
             Err(e) => {
                 debug!("{}", e);
                 debug!("Ignoring invalid packet");
+                self.notify_dropped(datagram, src, DropReason::Malformed);
                 return Ok((0, self.connected_to));
             }
         };
         debug!("received {:?}", packet);
 
+        // The remote peer received the reply to its handshake, the listener may forget it
+        if self.handshake.is_some() && src == self.connected_to &&
+            self.belongs_to_connection(&packet) {
+            self.forget_handshake();
+        }
+
         // Process packet, including sending a reply if necessary
         if let Some(mut pkt) = try!(self.handle_packet(&packet, src)) {
-            pkt.set_wnd_size(WINDOW_SIZE);
-            try!(self.socket.send_to(pkt.as_ref(), src));
+            let wnd_size = self.throttle_incoming(&packet);
+            pkt.set_wnd_size(wnd_size);
+            try!(self.send_datagram(pkt.as_ref(), src, false));
+            if let (PacketType::Reset, Some(metrics)) = (pkt.get_type(), self.metrics.as_ref()) {
+                metrics.counters().reset_sent();
+            }
             debug!("sent {:?}", pkt);
-        }
-
-        // Insert data packet into the incoming buffer if it isn't a duplicate of a previously
-        // discarded packet
-        if packet.get_type() == PacketType::Data &&
-           packet.seq_nr().wrapping_sub(self.last_dropped) > 0 {
-            self.insert_into_buffer(packet);
+            self.buffers.put(pkt.into_buffer());
         }
 
         // Flush incoming buffer if possible
@@ -421,21 +778,54 @@ impl UtpSocket {
         Ok((read, src))
     }
 
+    /// Handles every datagram the underlying UDP socket already received, without blocking, and
+    /// resends overdue packets. Received data stays in the incoming buffer.
+    fn poll_incoming(&mut self) -> Result<()> {
+        let mut b = [0; BUF_SIZE + HEADER_SIZE];
+        try!(self.socket.set_nonblocking(true));
+        let result = loop {
+            match self.socket.recv_from(&mut b) {
+                Ok((read, src)) => {
+                    self.notify_received(&b[..read], src);
+                    if let Err(e) = self.handle_datagram(&b[..read], src, &mut []) {
+                        break Err(e);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        try!(self.socket.set_nonblocking(false));
+        try!(result);
+
+        let timeout = Duration::from_millis(self.congestion_timeout);
+        if self.send_window.iter().any(|p| p.last_sent.elapsed() >= timeout) {
+            try!(self.handle_receive_timeout());
+        }
+
+        Ok(())
+    }
+
     fn handle_receive_timeout(&mut self) -> Result<()> {
+        self.stats.timeouts += 1;
+        self.notify_event(|handler, peer| handler.on_timeout(peer));
+
+        // Packets sent at least one retransmission timeout ago are overdue
+        let timeout = Duration::from_millis(self.congestion_timeout);
         self.congestion_timeout *= 2;
         self.cwnd = MSS;
 
         // There are three possible cases here:
         //
         // - If the socket is sending and waiting for acknowledgements (the send window is
-        //   not empty), resend the first unacknowledged packet;
+        //   not empty), resend every unacknowledged packet whose timer expired;
         //
         // - If the socket is not sending and it hasn't sent a FIN yet, then it's waiting
         //   for incoming packets: send a fast resend request;
         //
         // - If the socket sent a FIN previously, resend it.
         debug!("self.send_window: {:?}",
-               self.send_window.iter().map(Packet::seq_nr).collect::<Vec<u16>>());
+               self.send_window.iter().map(|p| p.packet.seq_nr()).collect::<Vec<u16>>());
 
         if self.send_window.is_empty() {
             // The socket is trying to close, all sent packets were acknowledged, and it has
@@ -449,7 +839,7 @@ impl UtpSocket {
                 packet.set_type(PacketType::Fin);
 
                 // Send FIN
-                try!(self.socket.send_to(packet.as_ref(), self.connected_to));
+                try!(self.send_datagram(packet.as_ref(), self.connected_to, true));
                 debug!("resent FIN: {:?}", packet);
             } else if self.state != SocketState::New {
                 // The socket is waiting for incoming packets but the remote peer is silent:
@@ -459,18 +849,65 @@ impl UtpSocket {
             }
         } else {
             // The socket is sending data packets but there is no reply from the remote
-            // peer: resend the first unacknowledged packet with the current timestamp.
-            let mut packet = &mut self.send_window[0];
-            packet.set_timestamp(now_microseconds());
-            try!(self.socket.send_to(packet.as_ref(), self.connected_to));
-            debug!("resent {:?}", packet);
+            // peer: resend every overdue packet with the current timestamp.
+            let overdue: Vec<u16> = self.send_window.iter()
+                .filter(|p| p.last_sent.elapsed() >= timeout)
+                .map(|p| p.packet.seq_nr())
+                .collect();
+            for seq_nr in overdue {
+                let mut packet = match self.send_window.get(seq_nr) {
+                    Some(sent) => sent.packet.clone(),
+                    None => continue,
+                };
+                try!(self.transmit(&mut packet, true));
+                if let Some(sent) = self.send_window.get_mut(seq_nr) {
+                    sent.packet = packet;
+                    sent.transmissions += 1;
+                    sent.last_sent = Instant::now();
+                }
+            }
         }
 
         Ok(())
     }
 
-    fn prepare_reply(&self, original: &Packet, t: PacketType) -> Packet {
-        let mut resp = Packet::new();
+    /// Accounts for an incoming packet in the receive rate limiter, if any, and returns the window
+    /// size to advertise in its reply.
+    ///
+    /// When the limiter is in debt the reply is delayed until the debt is paid, which paces the
+    /// remote peer without dropping any data. The advertised window then covers what the limiter
+    /// lets through over a round trip, and at least one full packet.
+    fn throttle_incoming(&self, packet: &PacketRef) -> u32 {
+        let window = self.receive_window();
+        match self.recv_rate_limiter {
+            None => window,
+            Some(ref limiter) => {
+                let delay = limiter.reserve(packet.payload().len());
+                if delay > Duration::from_secs(0) {
+                    debug!("receive rate limited, delaying reply by {:?}", delay);
+                    thread::sleep(delay);
+                }
+                let per_rtt = max(limiter.rate() * max(self.rtt, 0) as u64 / 1000, MSS as u64);
+                let wnd_size = min(max(limiter.available(window as u64), per_rtt),
+                                   window as u64) as u32;
+                debug!("advertised window: {}", wnd_size);
+                wnd_size
+            }
+        }
+    }
+
+    /// Returns the receive window: the payload that still fits in the incoming buffer past the
+    /// last acknowledged packet, in full-sized packets.
+    fn receive_window(&self) -> u32 {
+        let room = self.incoming_buffer.room_after(self.ack_nr) * (MSS as usize - HEADER_SIZE);
+        min(room, WINDOW_SIZE as usize) as u32
+    }
+
+    /// Builds the reply to a packet in a pooled buffer, which `handle_datagram` returns to the
+    /// pool once the reply is sent.
+    fn prepare_reply(&mut self, original: &PacketRef, t: PacketType) -> Packet {
+        let buffer = self.buffers.get();
+        let mut resp = Packet::with_payload_in(buffer, &[]);
         resp.set_type(t);
         let self_t_micro = now_microseconds();
         let other_t_micro = original.timestamp();
@@ -484,64 +921,52 @@ impl UtpSocket {
         resp
     }
 
-    /// Removes a packet in the incoming buffer and updates the current acknowledgement number.
+    /// Removes the next packet to be read from the incoming buffer and updates the current
+    /// acknowledgement number.
     fn advance_incoming_buffer(&mut self) -> Option<Packet> {
-        if !self.incoming_buffer.is_empty() {
-            let packet = self.incoming_buffer.remove(0);
+        let packet = self.incoming_buffer.pop_front();
+        self.pending_offset = 0;
+        if let Some(ref packet) = packet {
             debug!("Removed packet from incoming buffer: {:?}", packet);
-            self.ack_nr = packet.seq_nr();
-            self.last_dropped = self.ack_nr;
-            Some(packet)
-        } else {
-            None
+            // Never move the acknowledgement number backwards
+            let distance = packet.seq_nr().wrapping_sub(self.ack_nr);
+            if distance > 0 && distance < 0x8000 {
+                self.ack_nr = packet.seq_nr();
+            }
         }
+        packet
     }
 
-    /// Discards sequential, ordered packets in incoming buffer, starting from
-    /// the most recently acknowledged to the most recent, as long as there are
-    /// no missing packets. The discarded packets' payload is written to the
-    /// slice `buf`, starting in position `start`.
-    /// Returns the last written index.
+    /// Copies the payloads of every contiguous packet already received, starting from the next
+    /// packet to be read, into the slice `buf` until it's full, removing each packet from the
+    /// incoming buffer once it's been read in full.
+    ///
+    /// Returns the number of bytes written.
     fn flush_incoming_buffer(&mut self, buf: &mut [u8]) -> usize {
-        fn unsafe_copy(src: &[u8], dst: &mut [u8]) -> usize {
-            let max_len = min(src.len(), dst.len());
-            unsafe {
-                use std::ptr::copy;
-                copy(src.as_ptr(), dst.as_mut_ptr(), max_len);
-            }
-            return max_len;
-        }
-
-        // Return pending data from a partially read packet
-        if !self.pending_data.is_empty() {
-            let flushed = unsafe_copy(&self.pending_data[..], buf);
-
-            if flushed == self.pending_data.len() {
-                self.pending_data.clear();
-                self.advance_incoming_buffer();
-            } else {
-                self.pending_data = self.pending_data[flushed..].to_vec();
-            }
-
-            return flushed;
-        }
-
-        if !self.incoming_buffer.is_empty() &&
-            (self.ack_nr == self.incoming_buffer[0].seq_nr() ||
-             self.ack_nr + 1 == self.incoming_buffer[0].seq_nr())
-        {
-            let flushed = unsafe_copy(&self.incoming_buffer[0].payload()[..], buf);
+        let mut flushed = 0;
+
+        while flushed < buf.len() {
+            let (copied, remaining) = match self.incoming_buffer.front() {
+                None => break,
+                Some(packet) => {
+                    let payload = &packet.payload()[self.pending_offset..];
+                    let copied = min(payload.len(), buf.len() - flushed);
+                    buf[flushed..flushed + copied].copy_from_slice(&payload[..copied]);
+                    (copied, payload.len() - copied)
+                }
+            };
 
-            if flushed == self.incoming_buffer[0].payload().len() {
-                self.advance_incoming_buffer();
+            if remaining == 0 {
+                if let Some(packet) = self.advance_incoming_buffer() {
+                    self.buffers.put(packet.into_buffer());
+                }
             } else {
-                self.pending_data = self.incoming_buffer[0].payload()[flushed..].to_vec();
+                self.pending_offset += copied;
             }
-
-            return flushed;
+            flushed += copied;
         }
 
-        return 0;
+        flushed
     }
 
     /// Sends data on the socket to the remote peer. On success, returns the number of bytes
@@ -555,15 +980,39 @@ impl UtpSocket {
     //
     // Note that the buffer passed to `send_to` might exceed the maximum packet
     // size, which will result in the data being split over several packets.
+    //
+    // Only as much data as fits in the send buffer is accepted. If the buffer is
+    // full, this method waits for acknowledgements to make room, unless the socket
+    // is in non-blocking mode.
     pub fn send_to(&mut self, buf: &[u8]) -> Result<usize> {
         if self.state == SocketState::Closed {
-            return Err(SocketError::ConnectionClosed.into());
+            return Err(UtpError::ConnectionClosed.into());
+        }
+        if self.state == SocketState::Aborted {
+            return Err(UtpError::ConnectionAborted.into());
+        }
+
+        if self.nonblocking {
+            try!(self.poll_incoming());
+            try!(self.send_available());
         }
 
-        let total_length = buf.len();
+        // Wait for room for at least one byte of payload
+        let mut room = self.send_buffer_room();
+        while !buf.is_empty() && room <= HEADER_SIZE {
+            if self.nonblocking {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            try!(self.recv(&mut []));
+            room = self.send_buffer_room();
+        }
 
-        for chunk in buf.chunks(MSS as usize - HEADER_SIZE) {
-            let mut packet = Packet::with_payload(chunk);
+        let mut written = 0;
+        while written < buf.len() && room > HEADER_SIZE &&
+            self.buffered_packets() < MAX_BUFFERED_PACKETS {
+            let len = min(min(buf.len() - written, MSS as usize - HEADER_SIZE), room - HEADER_SIZE);
+            let buffer = self.buffers.get();
+            let mut packet = Packet::with_payload_in(buffer, &buf[written..written + len]);
             packet.set_seq_nr(self.seq_nr);
             packet.set_ack_nr(self.ack_nr);
             packet.set_connection_id(self.sender_connection_id);
@@ -572,16 +1021,51 @@ impl UtpSocket {
 
             // Intentionally wrap around sequence number
             self.seq_nr = self.seq_nr.wrapping_add(1);
+
+            written += len;
+            room -= len + HEADER_SIZE;
         }
 
-        // Send every packet in the queue
-        try!(self.send());
+        if self.nonblocking {
+            try!(self.send_available());
+        } else {
+            // Send every packet in the queue
+            try!(self.send());
+        }
+
+        Ok(written)
+    }
+
+    /// Returns the size in bytes of the unsent and unacknowledged packets.
+    fn buffered_bytes(&self) -> usize {
+        self.unsent_queue.iter().fold(self.curr_window as usize, |acc, p| acc + p.len())
+    }
+
+    /// Returns the number of sequence numbers taken by unsent and unacknowledged packets, from
+    /// the oldest unacknowledged one.
+    fn buffered_packets(&self) -> usize {
+        let oldest = self.send_window.front_seq_nr()
+            .or_else(|| self.unsent_queue.front().map(|packet| packet.seq_nr()))
+            .unwrap_or(self.seq_nr);
+        self.seq_nr.wrapping_sub(oldest) as usize
+    }
 
-        Ok(total_length)
+    /// Returns how many bytes, headers included, may still be written to the send buffer.
+    fn send_buffer_room(&self) -> usize {
+        if self.buffered_packets() >= MAX_BUFFERED_PACKETS {
+            0
+        } else {
+            self.send_buffer_size.saturating_sub(self.buffered_bytes())
+        }
     }
 
-    /// Consumes acknowledgements for every pending packet.
+    /// Sends every unsent packet and consumes acknowledgements for every pending packet.
     pub fn flush(&mut self) -> Result<()> {
+        if self.state == SocketState::Aborted {
+            return Err(UtpError::ConnectionAborted.into());
+        }
+        try!(self.send());
+
         let mut buf = [0u8; BUF_SIZE];
         while !self.send_window.is_empty() {
             debug!("packets in send window: {}", self.send_window.len());
@@ -596,17 +1080,40 @@ impl UtpSocket {
         while let Some(mut packet) = self.unsent_queue.pop_front() {
             try!(self.send_packet(&mut packet));
             self.curr_window += packet.len() as u32;
-            self.send_window.push(packet);
+            self.send_window.push_back(packet.seq_nr(), SentPacket::new(packet));
+        }
+        Ok(())
+    }
+
+    /// Sends packets in the unsent packet queue for as long as there is room for them in the
+    /// congestion and remote windows, without waiting for acknowledgements.
+    fn send_available(&mut self) -> Result<()> {
+        while self.curr_window < self.max_inflight() {
+            match self.unsent_queue.pop_front() {
+                None => break,
+                Some(mut packet) => {
+                    try!(self.send_packet(&mut packet));
+                    self.curr_window += packet.len() as u32;
+                    self.send_window.push_back(packet.seq_nr(), SentPacket::new(packet));
+                }
+            }
         }
         Ok(())
     }
 
+    /// Returns how many bytes may be in flight, given the congestion and remote windows.
+    fn max_inflight(&self) -> u32 {
+        let max_inflight = min(self.cwnd, self.remote_wnd_size);
+        max(MIN_CWND * MSS, max_inflight)
+    }
+
     /// Send one packet.
     #[inline]
     fn send_packet(&mut self, packet: &mut Packet) -> Result<()> {
         debug!("current window: {}", self.send_window.len());
-        let max_inflight = min(self.cwnd, self.remote_wnd_size);
-        let max_inflight = max(MIN_CWND * MSS, max_inflight);
+        // Packets still in the send window were sent before
+        let retransmission = self.send_window.contains(packet.seq_nr());
+        let max_inflight = self.max_inflight();
         let now = now_microseconds();
 
         // Wait until enough in-flight packets are acknowledged for rate control purposes, but don't
@@ -632,14 +1139,107 @@ impl UtpSocket {
             return Ok(());
         }
 
+        self.transmit(packet, retransmission)
+    }
+
+    /// Sends a packet to the remote peer with a fresh timestamp, once pacing and the rate limiter,
+    /// if any, allow it.
+    fn transmit(&mut self, packet: &mut Packet, retransmission: bool) -> Result<()> {
+        // Spread packets over the round-trip time instead of sending them in a burst
+        if let (Some(interval), Some(last_sent)) = (self.pacing_interval(), self.last_sent) {
+            let elapsed = last_sent.elapsed();
+            if elapsed < interval {
+                debug!("pacing, waiting {:?}", interval - elapsed);
+                thread::sleep(interval - elapsed);
+            }
+        }
+
+        // Wait for the rate limiter, if any, to allow sending the packet
+        if let Some(ref limiter) = self.rate_limiter {
+            let delay = limiter.reserve(packet.len());
+            if delay > Duration::from_secs(0) {
+                debug!("rate limited, waiting {:?}", delay);
+                thread::sleep(delay);
+            }
+        }
+
         packet.set_timestamp(now_microseconds());
         packet.set_timestamp_difference(self.their_delay);
-        try!(self.socket.send_to(packet.as_ref(), self.connected_to));
+        try!(self.send_datagram(packet.as_ref(), self.connected_to, retransmission));
+        self.last_sent = Some(Instant::now());
         debug!("sent {:?}", packet);
 
         Ok(())
     }
 
+    /// Moves the socket to a new state, keeping the listener's connection counts up to date.
+    fn set_state(&mut self, state: SocketState) {
+        self.state = state;
+        if let Some(ref mut metrics) = self.metrics {
+            metrics.set_state(state.label());
+        }
+    }
+
+    /// Calls `f` with the event handler, if any, and the address of the remote peer.
+    fn notify_event<F: FnOnce(&dyn EventHandler, SocketAddr)>(&self, f: F) {
+        if let Some(ref handler) = self.event_handler {
+            f(&**handler, self.connected_to);
+        }
+    }
+
+    /// Sends a datagram to `dst`, recording it in the packet capture and notifying the observer,
+    /// if any.
+    fn send_datagram(&mut self, datagram: &[u8], dst: SocketAddr, retransmission: bool)
+                     -> Result<usize> {
+        let sent = try!(self.socket.send_to(datagram, dst));
+        count_sent(&mut self.stats, &self.metrics, datagram, retransmission);
+        record(&self.capture, &self.socket, dst, datagram, true);
+        if let Some(ref observer) = self.observer {
+            observer.on_sent(datagram, dst, retransmission);
+        }
+        Ok(sent)
+    }
+
+    /// Records a datagram received from `src` in the packet capture and notifies the observer, if
+    /// any.
+    fn notify_received(&mut self, datagram: &[u8], src: SocketAddr) {
+        self.stats.bytes_received += datagram.len() as u64;
+        self.stats.packets_received += 1;
+        if let Some(ref metrics) = self.metrics {
+            metrics.counters().add_received(datagram.len());
+        }
+        record(&self.capture, &self.socket, src, datagram, false);
+        if let Some(ref observer) = self.observer {
+            observer.on_received(datagram, src);
+        }
+    }
+
+    /// Notifies the observer, if any, that a datagram received from `src` was dropped.
+    fn notify_dropped(&mut self, datagram: &[u8], src: SocketAddr, reason: DropReason) {
+        match (reason, self.metrics.as_ref()) {
+            (DropReason::Duplicate, _) => self.stats.duplicate_packets += 1,
+            (DropReason::Malformed, Some(metrics)) => metrics.counters().invalid_packet(),
+            _ => (),
+        }
+        if let Some(ref observer) = self.observer {
+            observer.on_dropped(datagram, src, reason);
+        }
+    }
+
+    /// Calculates the time between consecutive packets needed to spread a full congestion window
+    /// over one round-trip time.
+    ///
+    /// Returns `None` if pacing is disabled or there is no round-trip time estimate yet.
+    fn pacing_interval(&self) -> Option<Duration> {
+        if !self.pacing || self.rtt <= 0 {
+            return None;
+        }
+
+        let packets_per_rtt = max(self.cwnd / MSS, 1) as u64;
+        let rtt_us = self.rtt as u64 * 1000;
+        Some(Duration::from_micros(rtt_us / packets_per_rtt))
+    }
+
     // Insert a new sample in the base delay list.
     //
     // The base delay list contains at most `BASE_HISTORY` samples, each sample is the minimum
@@ -712,54 +1312,66 @@ impl UtpSocket {
     }
 
     /// Builds the selective acknowledgement extension data for usage in packets.
+    ///
+    /// Bit `i` of the extension marks the reception of the packet with sequence number
+    /// `ack_nr + 2 + i`. Like libutp, only the `SACK_LEN * 8` packets following `ack_nr + 1` are
+    /// described; packets further ahead stay buffered but aren't reported until the gap closes.
+    ///
+    /// Returns an empty vector if no buffered packet can be reported.
     fn build_selective_ack(&self) -> Vec<u8> {
-        let stashed = self.incoming_buffer.iter()
-            .filter(|pkt| pkt.seq_nr() > self.ack_nr + 1)
-            .map(|pkt| (pkt.seq_nr() - self.ack_nr - 2) as usize)
-            .map(|diff| (diff / 8, diff % 8));
-
-        let mut sack = Vec::new();
-        for (byte, bit) in stashed {
-            // Make sure the amount of elements in the SACK vector is a
-            // multiple of 4 and enough to represent the lost packets
-            while byte >= sack.len() || sack.len() % 4 != 0 {
-                sack.push(0u8);
+        let mut sack = Bitfield::with_bytes(SACK_LEN);
+        for bit in 0..sack.len() {
+            if self.incoming_buffer.contains(self.ack_nr.wrapping_add(2 + bit as u16)) {
+                sack.set(bit);
             }
-
-            sack[byte] |= 1 << bit;
         }
 
-        return sack;
+        if sack.any() {
+            sack.into_bytes()
+        } else {
+            Vec::new()
+        }
     }
 
     /// Sends a fast resend request to the remote peer.
     ///
     /// A fast resend request consists of sending three State packets (acknowledging the last
     /// received packet) in quick succession.
-    fn send_fast_resend_request(&self) {
+    fn send_fast_resend_request(&mut self) {
+        let mut buf = [0; HEADER_SIZE];
         for _ in 0..3 {
-            let mut packet = Packet::new();
-            packet.set_type(PacketType::State);
+            let mut packet = PacketEncoder::new(&mut buf, PacketType::State);
             let self_t_micro = now_microseconds();
             packet.set_timestamp(self_t_micro);
             packet.set_timestamp_difference(self.their_delay);
             packet.set_connection_id(self.sender_connection_id);
             packet.set_seq_nr(self.seq_nr);
             packet.set_ack_nr(self.ack_nr);
-            let _ = self.socket.send_to(packet.as_ref(), self.connected_to);
+            let _ = self.send_datagram(packet.finish().as_ref(), self.connected_to, false);
         }
     }
 
     fn resend_lost_packet(&mut self, lost_packet_nr: u16) {
         debug!("---> resend_lost_packet({}) <---", lost_packet_nr);
-        match self.send_window.iter().position(|pkt| pkt.seq_nr() == lost_packet_nr) {
+        match self.send_window.get(lost_packet_nr).map(|p| p.packet.clone()) {
             None => debug!("Packet {} not found", lost_packet_nr),
-            Some(position) => {
+            Some(mut packet) => {
                 debug!("self.send_window.len(): {}", self.send_window.len());
-                debug!("position: {}", position);
-                let mut packet = self.send_window[position].clone();
-                // FIXME: Unchecked result
-                let _ = self.send_packet(&mut packet);
+                // A packet that couldn't be sent keeps its bookkeeping, so that it's still taken
+                // for lost and its acknowledgement still yields a round-trip time sample
+                if let Err(e) = self.send_packet(&mut packet) {
+                    debug!("failed to resend packet {}: {}", lost_packet_nr, e);
+                    return;
+                }
+                self.stats.fast_retransmits += 1;
+
+                // Sending may have processed acknowledgements and moved the packet, so look it up
+                // again before updating its bookkeeping
+                if let Some(sent) = self.send_window.get_mut(lost_packet_nr) {
+                    sent.packet = packet;
+                    sent.transmissions += 1;
+                    sent.last_sent = Instant::now();
+                }
 
                 // We intentionally don't increase `curr_window` because otherwise a packet's length
                 // would be counted more than once
@@ -769,7 +1381,9 @@ impl UtpSocket {
     }
 
     /// Forgets sent packets that were acknowledged by the remote peer.
-    fn advance_send_window(&mut self) {
+    ///
+    /// Returns the total size of the packets forgotten.
+    fn advance_send_window(&mut self) -> usize {
         // The reason I'm not removing the first element in a loop while its sequence number is
         // smaller than `last_acked` is because of wrapping sequence numbers, which would create the
         // sequence [..., 65534, 65535, 0, 1, ...]. If `last_acked` is smaller than the first
@@ -777,36 +1391,61 @@ impl UtpSocket {
         // removed, as the condition `seq_nr < last_acked` would fail immediately.
         //
         // On the other hand, I can't keep removing the first packet in a loop until its sequence
-        // number matches `last_acked` because it might never match, and in that case no packets
-        // should be removed.
-        if let Some(position) = self.send_window.iter()
-                                    .position(|packet| packet.seq_nr() == self.last_acked) {
-            for _ in 0..position + 1 {
-                let packet = self.send_window.remove(0);
-                self.curr_window -= packet.len() as u32;
+        // number matches `last_acked` because it might never match (the packet may have been
+        // selectively acknowledged and removed already, or `last_acked` may be unrelated to the
+        // packets in flight), and in the latter case no packets should be removed.
+        //
+        // Instead, remove packets from the front for as long as `last_acked` lies between their
+        // sequence number and the next sequence number to be used, wrapping around if needed.
+        let mut bytes_acked = 0;
+        while let Some(seq_nr) = self.send_window.front_seq_nr() {
+            if self.last_acked.wrapping_sub(seq_nr) >= self.seq_nr.wrapping_sub(seq_nr) {
+                break;
+            }
+            if let Some(sent) = self.send_window.pop_front() {
+                bytes_acked += sent.packet.len();
+                self.curr_window -= sent.packet.len() as u32;
+                self.buffers.put(sent.packet.into_buffer());
             }
         }
         debug!("self.curr_window: {}", self.curr_window);
+        bytes_acked
+    }
+
+    /// Returns whether a packet belongs to this connection, judging by its connection id.
+    fn belongs_to_connection(&self, packet: &PacketRef) -> bool {
+        packet.get_type() == PacketType::Syn || self.state == SocketState::SynSent ||
+            packet.connection_id() == self.sender_connection_id ||
+            packet.connection_id() == self.receiver_connection_id
     }
 
     /// Handles an incoming packet, updating socket state accordingly.
     ///
     /// Returns the appropriate reply packet, if needed.
-    fn handle_packet(&mut self, packet: &Packet, src: SocketAddr) -> Result<Option<Packet>> {
+    fn handle_packet(&mut self, packet: &PacketRef, src: SocketAddr) -> Result<Option<Packet>> {
         debug!("({:?}, {:?})", self.state, packet.get_type());
 
-        // Acknowledge only if the packet strictly follows the previous one
-        if packet.seq_nr().wrapping_sub(self.ack_nr) == 1 {
+        // Acknowledge only if the packet strictly follows the previous one. Data packets are only
+        // acknowledged once stored in the incoming buffer.
+        if packet.get_type() != PacketType::Data && packet.seq_nr().wrapping_sub(self.ack_nr) == 1 {
             self.ack_nr = packet.seq_nr();
         }
 
         // Reset connection if connection id doesn't match and this isn't a SYN
-        if packet.get_type() != PacketType::Syn && self.state != SocketState::SynSent &&
-           !(packet.connection_id() == self.sender_connection_id ||
-             packet.connection_id() == self.receiver_connection_id) {
+        if !self.belongs_to_connection(packet) {
+            self.notify_dropped(packet.as_ref(), src, DropReason::WrongConnectionId);
             return Ok(Some(self.prepare_reply(packet, PacketType::Reset)));
         }
 
+        // A listener may hand the connection over to another port once the handshake completes
+        if self.peer_may_move && self.state == SocketState::Connected {
+            self.peer_may_move = false;
+            if src != self.connected_to && src.ip() == self.connected_to.ip() {
+                debug!("remote peer moved from {} to {}", self.connected_to, src);
+                self.connected_to = src;
+            }
+        }
+
         // Update remote window size
         self.remote_wnd_size = packet.wnd_size();
         debug!("self.remote_wnd_size: {}", self.remote_wnd_size);
@@ -818,29 +1457,35 @@ impl UtpSocket {
 
         match (self.state, packet.get_type()) {
             (SocketState::New, PacketType::Syn) => {
-                self.connected_to = src;
-                self.ack_nr = packet.seq_nr();
-                self.seq_nr = rand::random();
-                self.receiver_connection_id = packet.connection_id() + 1;
-                self.sender_connection_id = packet.connection_id();
-                self.state = SocketState::Connected;
-                self.last_dropped = self.ack_nr;
-
+                self.establish(src, packet.connection_id(), packet.seq_nr(), rand::random());
                 Ok(Some(self.prepare_reply(packet, PacketType::State)))
             }
+            // The remote peer didn't receive our reply to its SYN, send it again
+            (SocketState::Connected, PacketType::Syn) if src == self.connected_to &&
+                packet.connection_id() == self.sender_connection_id => {
+                // Any data sent since must not be taken for acknowledged by the remote peer
+                let mut reply = self.prepare_reply(packet, PacketType::State);
+                reply.set_seq_nr(self.initial_seq_nr);
+                reply.set_ack_nr(packet.seq_nr());
+                Ok(Some(reply))
+            }
             (_, PacketType::Syn) => Ok(Some(self.prepare_reply(packet, PacketType::Reset))),
             (SocketState::SynSent, PacketType::State) => {
                 self.connected_to = src;
                 self.ack_nr = packet.seq_nr();
                 self.seq_nr += 1;
-                self.state = SocketState::Connected;
+                self.set_state(SocketState::Connected);
+                self.notify_event(|handler, peer| handler.on_connected(peer));
+                // The remote peer's first data packet reuses the sequence number of its reply
+                self.incoming_buffer.reset(self.ack_nr);
                 self.last_acked = packet.ack_nr();
                 self.last_acked_timestamp = now_microseconds();
+                self.peer_may_move = true;
                 Ok(None)
             }
-            (SocketState::SynSent, _) => Err(SocketError::InvalidReply.into()),
+            (SocketState::SynSent, _) => Err(UtpError::InvalidReply.into()),
             (SocketState::Connected, PacketType::Data) |
-            (SocketState::FinSent, PacketType::Data) => Ok(self.handle_data_packet(packet)),
+            (SocketState::FinSent, PacketType::Data) => Ok(self.handle_data_packet(packet, src)),
             (SocketState::Connected, PacketType::State) => {
                 self.handle_state_packet(packet);
                 Ok(None)
@@ -864,7 +1509,9 @@ impl UtpSocket {
                 }
 
                 // Give up, the remote peer might not care about our missing packets
-                self.state = SocketState::Closed;
+                self.set_state(SocketState::Closed);
+                self.notify_event(|handler, peer| handler.on_fin_received(peer));
+                self.notify_event(|handler, peer| handler.on_closed(peer, CloseReason::Graceful));
                 Ok(Some(reply))
             }
             (SocketState::Closed, PacketType::Fin) => {
@@ -872,25 +1519,69 @@ impl UtpSocket {
             }
             (SocketState::FinSent, PacketType::State) => {
                 if packet.ack_nr() == self.seq_nr {
-                    self.state = SocketState::Closed;
+                    self.set_state(SocketState::Closed);
+                    self.notify_event(|handler, peer| {
+                        handler.on_closed(peer, CloseReason::Graceful)
+                    });
                 } else {
                     self.handle_state_packet(packet);
                 }
                 Ok(None)
             }
             (_, PacketType::Reset) => {
-                self.state = SocketState::ResetReceived;
-                Err(SocketError::ConnectionReset.into())
+                if let Some(ref metrics) = self.metrics {
+                    metrics.counters().reset_received();
+                }
+                self.set_state(SocketState::ResetReceived);
+                self.notify_event(|handler, peer| handler.on_reset_received(peer));
+                self.notify_event(|handler, peer| handler.on_closed(peer, CloseReason::Reset));
+                Err(UtpError::ConnectionReset.into())
             }
             (state, ty) => {
                 let message = format!("Unimplemented handling for ({:?},{:?})", state, ty);
                 debug!("{}", message);
-                Err(SocketError::Other(message).into())
+                Err(UtpError::Other(message).into())
             }
         }
     }
 
-    fn handle_data_packet(&mut self, packet: &Packet) -> Option<Packet> {
+    /// Establishes the connection requested by a SYN received from `src`, with the given
+    /// connection id and sequence number. `seq_nr` is the initial sequence number of the reply.
+    fn establish(&mut self, src: SocketAddr, connection_id: u16, syn_seq_nr: u16, seq_nr: u16) {
+        self.connected_to = src;
+        self.ack_nr = syn_seq_nr;
+        self.seq_nr = seq_nr;
+        self.initial_seq_nr = seq_nr;
+        // Nothing was acknowledged yet, so the first data packet must not be taken for acked
+        self.last_acked = seq_nr.wrapping_sub(1);
+        self.receiver_connection_id = connection_id.wrapping_add(1);
+        self.sender_connection_id = connection_id;
+        self.set_state(SocketState::Connected);
+        self.notify_event(|handler, peer| handler.on_connected(peer));
+        self.incoming_buffer.reset(self.ack_nr.wrapping_add(1));
+    }
+
+    fn handle_data_packet(&mut self, packet: &PacketRef, src: SocketAddr) -> Option<Packet> {
+        let seq_nr = packet.seq_nr();
+        let buffer = self.buffers.get();
+        if let Err(rejected) = self.insert_into_buffer(packet.to_packet_in(buffer)) {
+            self.buffers.put(rejected.into_buffer());
+            let distance = seq_nr.wrapping_sub(self.ack_nr);
+            if distance == 0 || distance >= 0x8000 || self.incoming_buffer.contains(seq_nr) {
+                // Acknowledge duplicates again, in case the original acknowledgement was lost
+                self.notify_dropped(packet.as_ref(), src, DropReason::Duplicate);
+            } else {
+                // Leave the packet unacknowledged, so that the remote peer sends it again later
+                self.notify_dropped(packet.as_ref(), src, DropReason::BufferFull);
+                return None;
+            }
+        }
+
+        // Acknowledge every packet received in order so far
+        while self.incoming_buffer.contains(self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+        }
+
         // If a FIN was previously sent, reply with a FIN packet acknowledging the received packet.
         let packet_type = if self.state == SocketState::FinSent {
             PacketType::Fin
@@ -961,7 +1652,7 @@ impl UtpSocket {
         debug!("max_allowed_cwnd: {}", max_allowed_cwnd);
     }
 
-    fn handle_state_packet(&mut self, packet: &Packet) {
+    fn handle_state_packet(&mut self, packet: &PacketRef) {
         if packet.ack_nr() == self.last_acked {
             self.duplicate_ack_count += 1;
         } else {
@@ -971,20 +1662,29 @@ impl UtpSocket {
         }
 
         // Update congestion window size
-        if let Some(index) = self.send_window.iter().position(|p| packet.ack_nr() == p.seq_nr()) {
-            // Calculate the sum of the size of every packet implicitly and explicitly acknowledged
-            // by the inbound packet (i.e., every packet whose sequence number precedes the inbound
-            // packet's acknowledgement number, plus the packet whose sequence number matches)
-            let bytes_newly_acked = self.send_window.iter()
-                .take(index + 1)
-                .fold(0, |acc, p| acc + p.len());
-
-            // Update base and current delay
-            let now = now_microseconds();
-            let our_delay = now - self.send_window[index].timestamp();
-            debug!("our_delay: {}", our_delay);
-            self.update_base_delay(our_delay, now);
-            self.update_current_delay(our_delay, now);
+        let acked = self.send_window.get(packet.ack_nr())
+                        .map(|p| (p.transmissions, p.packet.timestamp()));
+
+        // Forget every packet implicitly and explicitly acknowledged by the inbound packet (i.e.,
+        // every packet whose sequence number precedes the inbound packet's acknowledgement number,
+        // plus the packet whose sequence number matches), adding up their sizes
+        let bytes_newly_acked = self.advance_send_window();
+
+        if let Some((transmissions, timestamp)) = acked {
+            // Update base and current delay, following Karn's algorithm: the acknowledgement of a
+            // retransmitted packet can't be matched to a specific transmission, so it yields no
+            // delay or round-trip time samples.
+            let our_delay = if transmissions == 1 {
+                let now = now_microseconds();
+                let our_delay = now - timestamp;
+                debug!("our_delay: {}", our_delay);
+                self.update_base_delay(our_delay, now);
+                self.update_current_delay(our_delay, now);
+                Some(our_delay)
+            } else {
+                debug!("ignoring delay sample of retransmitted packet");
+                None
+            };
 
             let off_target: f64 = (TARGET - u32::from(self.queuing_delay()) as f64) / TARGET;
             debug!("off_target: {}", off_target);
@@ -992,36 +1692,23 @@ impl UtpSocket {
             self.update_congestion_window(off_target, bytes_newly_acked as u32);
 
             // Update congestion timeout
-            let rtt = u32::from(our_delay - self.queuing_delay()) / 1000; // in milliseconds
-            self.update_congestion_timeout(rtt as i32);
+            if let Some(our_delay) = our_delay {
+                let rtt = u32::from(our_delay - self.queuing_delay()) / 1000; // in milliseconds
+                self.update_congestion_timeout(rtt as i32);
+            }
         }
 
         let mut packet_loss_detected: bool = !self.send_window.is_empty() &&
-                                             self.duplicate_ack_count == 3;
+                                             self.duplicate_ack_count ==
+                                             DUPLICATE_ACKS_BEFORE_RESEND;
 
         // Process extensions, if any
         for extension in packet.extensions() {
             if extension.get_type() == ExtensionType::SelectiveAck {
-                // If three or more packets are acknowledged past the implicit missing one,
-                // assume it was lost.
-                if extension.iter().count_ones() >= 3 {
-                    self.resend_lost_packet(packet.ack_nr() + 1);
+                self.stats.sacks_received += 1;
+                if self.handle_selective_ack(packet.ack_nr(), &extension) {
                     packet_loss_detected = true;
                 }
-
-                if let Some(last_seq_nr) = self.send_window.last().map(Packet::seq_nr) {
-                    let lost_packets = extension.iter()
-                        .enumerate()
-                        .filter(|&(_, received)| !received)
-                        .map(|(idx, _)| packet.ack_nr() + 2 + idx as u16)
-                        .take_while(|&seq_nr| seq_nr < last_seq_nr);
-
-                    for seq_nr in lost_packets {
-                        debug!("SACK: packet {} lost", seq_nr);
-                        self.resend_lost_packet(seq_nr);
-                        packet_loss_detected = true;
-                    }
-                }
             } else {
                 debug!("Unknown extension {:?}, ignoring", extension.get_type());
             }
@@ -1030,49 +1717,123 @@ impl UtpSocket {
         // Three duplicate ACKs mean a fast resend request. Resend the first unacknowledged packet
         // if the incoming packet doesn't have a SACK extension. If it does, the lost packets were
         // already resent.
-        if !self.send_window.is_empty() && self.duplicate_ack_count == 3 &&
+        if !self.send_window.is_empty() &&
+           self.duplicate_ack_count == DUPLICATE_ACKS_BEFORE_RESEND &&
            !packet.extensions().any(|ext| ext.get_type() == ExtensionType::SelectiveAck) {
-            self.resend_lost_packet(packet.ack_nr() + 1);
+            self.resend_lost_packet(packet.ack_nr().wrapping_add(1));
         }
 
         // Packet lost, halve the congestion window
         if packet_loss_detected {
             debug!("packet loss detected, halving congestion window");
+            self.notify_event(|handler, peer| handler.on_loss(peer));
             self.cwnd = max(self.cwnd / 2, MIN_CWND * MSS);
             debug!("cwnd: {}", self.cwnd);
         }
-
-        // Success, advance send window
-        self.advance_send_window();
     }
 
-    /// Inserts a packet into the socket's buffer.
+    /// Processes a selective acknowledgement extension, as libutp does.
     ///
-    /// The packet is inserted in such a way that the packets in the buffer are sorted according to
-    /// their sequence number in ascending order. This allows storing packets that were received out
-    /// of order.
+    /// Bit `i` of the extension refers to the packet with sequence number `ack_nr + 2 + i`, the
+    /// packet `ack_nr + 1` being implicitly missing. Selectively acknowledged packets are removed
+    /// from the send window, so they are never retransmitted. A missing packet is only considered
+    /// lost if at least three packets sent after it were acknowledged, and each lost packet is
+    /// resent at most once per round-trip time.
     ///
-    /// Trying to insert a duplicate of a packet will silently fail.
-    /// it's more recent (larger timestamp).
-    fn insert_into_buffer(&mut self, packet: Packet) {
-        // Immediately push to the end if the packet's sequence number comes after the last
-        // packet's.
-        if self.incoming_buffer.last().map_or(false, |p| packet.seq_nr() > p.seq_nr()) {
-            self.incoming_buffer.push(packet);
-        } else {
-            // Find index following the most recent packet before the one we wish to insert
-            let i = self.incoming_buffer.iter().filter(|p| p.seq_nr() < packet.seq_nr()).count();
+    /// Returns whether any lost packet was resent.
+    fn handle_selective_ack(&mut self, ack_nr: u16, extension: &Extension) -> bool {
+        // Reception status of every packet the extension describes, starting at `ack_nr + 1`
+        let received = Some(false).into_iter().chain(extension.iter()).collect::<Vec<bool>>();
+
+        // Forget selectively acknowledged packets
+        for (idx, _) in received.iter().enumerate().filter(|&(_, &received)| received) {
+            let seq_nr = ack_nr.wrapping_add(1 + idx as u16);
+            if let Some(sent) = self.send_window.remove(seq_nr) {
+                self.curr_window -= sent.packet.len() as u32;
+                self.buffers.put(sent.packet.into_buffer());
+                debug!("SACK: packet {} acknowledged", seq_nr);
+            }
+        }
+
+        // Walk back from the most recent packet, counting how many packets were acknowledged
+        // after each missing one
+        let mut lost = Vec::new();
+        let mut acked_after = 0;
+        for (idx, &received) in received.iter().enumerate().rev() {
+            if received {
+                acked_after += 1;
+            } else if acked_after >= DUPLICATE_ACKS_BEFORE_RESEND {
+                lost.push(ack_nr.wrapping_add(1 + idx as u16));
+            }
+        }
 
-            if self.incoming_buffer.get(i).map_or(true, |p| p.seq_nr() != packet.seq_nr()) {
-                self.incoming_buffer.insert(i, packet);
+        // Without any round-trip time sample yet, wait for the retransmission timeout instead
+        let rtt = if self.rtt > 0 {
+            Duration::from_millis(self.rtt as u64)
+        } else {
+            Duration::from_millis(self.congestion_timeout)
+        };
+        let mut resent = false;
+        for seq_nr in lost.into_iter().rev() {
+            let resend = self.send_window.get(seq_nr)
+                .map_or(false, |p| p.last_sent.elapsed() >= rtt);
+
+            if resend {
+                debug!("SACK: packet {} lost", seq_nr);
+                self.resend_lost_packet(seq_nr);
+                resent = true;
             }
         }
+
+        resent
+    }
+
+    /// Removes this socket's handshake from the listener that accepted it, if it's still there.
+    fn forget_handshake(&mut self) {
+        if let Some((table, key)) = self.handshake.take() {
+            table.lock().unwrap().remove(&key);
+        }
+    }
+
+    /// Inserts a packet into the socket's buffer.
+    ///
+    /// The packet is stored in the slot for its sequence number, which allows storing packets that
+    /// were received out of order.
+    ///
+    /// Trying to insert a duplicate of a packet, a packet that was already read, or a packet too far
+    /// ahead of the next packet to be read to fit in the buffer fails and hands the packet back.
+    fn insert_into_buffer(&mut self, packet: Packet) -> ::std::result::Result<(), Packet> {
+        let seq_nr = packet.seq_nr();
+        let inserted = self.incoming_buffer.insert(seq_nr, packet);
+        if inserted.is_err() {
+            debug!("Dropping packet {}: duplicate or outside the receive window", seq_nr);
+        }
+        inserted
     }
 }
 
 impl Drop for UtpSocket {
     fn drop(&mut self) {
-        let _ = self.close();
+        self.forget_handshake();
+
+        if self.state == SocketState::Connected || self.state == SocketState::FinSent {
+            self.close_on_drop();
+        }
+
+        // Keep answering the remote peer for a while if the connection was closed gracefully
+        if self.state == SocketState::Closed {
+            if let (Some(time_wait), Ok(socket)) = (self.time_wait.take(),
+                                                    self.socket.try_clone()) {
+                let connection = ClosedConnection {
+                    peer: self.connected_to,
+                    receiver_connection_id: self.receiver_connection_id,
+                    sender_connection_id: self.sender_connection_id,
+                    seq_nr: self.seq_nr,
+                    ack_nr: self.ack_nr,
+                };
+                time_wait.insert(socket, connection);
+            }
+        }
     }
 }
 
@@ -1104,6 +1865,64 @@ impl Drop for UtpSocket {
 pub struct UtpListener {
     /// The public facing UDP socket
     socket: UdpSocket,
+
+    /// Limiter shared by every accepted socket
+    rate_limiter: Option<RateLimiter>,
+
+    /// Receive limiter shared by every accepted socket
+    recv_rate_limiter: Option<RateLimiter>,
+
+    /// Packet capture shared by every accepted socket
+    capture: Option<PacketCapture>,
+
+    /// Observer shared by every accepted socket
+    observer: Option<Arc<dyn PacketObserver>>,
+
+    /// Event handler shared by every accepted socket
+    event_handler: Option<Arc<dyn EventHandler>>,
+
+    /// Counters shared by every accepted socket
+    metrics: Arc<Counters>,
+
+    /// Closed connections of the accepted sockets waiting for late packets, if enabled
+    time_wait: Option<TimeWait>,
+
+    /// Recently accepted connections whose handshake may still be retransmitted, by remote
+    /// address and connection id
+    half_open: HalfOpenTable,
+
+    /// Number of pending handshakes past which SYNs are answered statelessly, if any
+    syn_cookie_threshold: Option<usize>,
+
+    /// Key of the stateless handshakes
+    cookies: CookieJar,
+
+    /// When the latest stateless handshake reply was sent
+    last_cookie: Mutex<Option<Instant>>,
+}
+
+/// Accepted connections whose remote peer may still be waiting for the reply to its SYN, shared
+/// by a listener and the sockets it accepted.
+type HalfOpenTable = Arc<Mutex<HashMap<(SocketAddr, u16), HalfOpen>>>;
+
+/// An accepted connection, as seen by the listener while the remote peer may still be waiting for
+/// the reply to its SYN.
+struct HalfOpen {
+    /// The accepted socket's UDP socket
+    socket: UdpSocket,
+
+    /// Sequence number of the SYN
+    seq_nr: u16,
+
+    /// The reply to the SYN, or to the first packet of a stateless handshake
+    reply: Packet,
+
+    /// When the SYN was first received
+    accepted_at: Instant,
+
+    /// Whether the remote peer already proved it receives the listener's packets, as in
+    /// stateless handshakes
+    confirmed: bool,
 }
 
 impl UtpListener {
@@ -1116,7 +1935,92 @@ impl UtpListener {
     ///
     /// If more than one valid address is specified, only the first will be used.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpListener> {
-        UdpSocket::bind(addr).and_then(|s| Ok(UtpListener { socket: s }))
+        UdpSocket::bind(addr).and_then(|s| {
+            Ok(UtpListener {
+                socket: s,
+                rate_limiter: None,
+                recv_rate_limiter: None,
+                capture: None,
+                observer: None,
+                event_handler: None,
+                metrics: Arc::new(Counters::new()),
+                time_wait: None,
+                half_open: Arc::new(Mutex::new(HashMap::new())),
+                syn_cookie_threshold: None,
+                cookies: CookieJar::new(),
+                last_cookie: Mutex::new(None),
+            })
+        })
+    }
+
+    /// Limits the aggregate rate at which the sockets accepted from now on send data.
+    ///
+    /// Every accepted socket receives a handle to the same `RateLimiter`, so changing its rate
+    /// affects all of them. Passing `None` removes the limit from future connections.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    /// Limits the aggregate rate at which the sockets accepted from now on receive data.
+    ///
+    /// See `UtpSocket::set_recv_rate_limiter` for details.
+    pub fn set_recv_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.recv_rate_limiter = limiter;
+    }
+
+    /// Records the datagrams of this listener and of every socket accepted from now on into a
+    /// pcap capture.
+    ///
+    /// Passing `None` stops recording future connections.
+    pub fn set_capture(&mut self, capture: Option<PacketCapture>) {
+        self.capture = capture;
+    }
+
+    /// Registers an observer notified of the packets received by this listener and of every packet
+    /// of the sockets accepted from now on.
+    ///
+    /// Passing `None` removes the observer from future connections.
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn PacketObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Registers a handler notified of the lifecycle events of every socket accepted from now on.
+    ///
+    /// Passing `None` removes the handler from future connections.
+    pub fn set_event_handler(&mut self, handler: Option<Arc<dyn EventHandler>>) {
+        self.event_handler = handler;
+    }
+
+    /// Answers SYNs without allocating anything once `threshold` handshakes are pending, to
+    /// withstand SYN floods. `Some(0)` always does, and `None`, the default, never does.
+    ///
+    /// In this mode the reply to a SYN comes from the listening port, and its initial sequence
+    /// number is a cookie: a keyed hash of the remote peer's address and of the SYN. A socket is
+    /// only created, and returned by `accept`, once the remote peer's first packet echoes the
+    /// cookie back, proving the peer's address isn't spoofed. The connection then moves to a port
+    /// of its own, which clients of this crate follow.
+    ///
+    /// Cookies only hold 16 bits, so a spoofed packet has one chance in 65536 of opening a
+    /// connection. Clients that wait for the server to speak first never send that packet, so
+    /// they can't connect while the mode is active.
+    pub fn set_syn_cookies(&mut self, threshold: Option<usize>) {
+        self.syn_cookie_threshold = threshold;
+    }
+
+    /// Keeps the connections accepted from now on open for `duration` after a graceful close.
+    /// `None`, the default, releases them right away.
+    ///
+    /// Once a gracefully closed socket is dropped, its UDP socket keeps answering the remote
+    /// peer, in case it didn't receive the final acknowledgement: retransmitted `Fin` packets are
+    /// acknowledged again, and late data is answered with a `Reset`. Meanwhile the socket's port
+    /// stays bound. A single background thread serves every closed connection of the listener.
+    pub fn set_time_wait(&mut self, duration: Option<Duration>) {
+        self.time_wait = duration.map(TimeWait::new);
+    }
+
+    /// Returns a handle on the counters of this listener and of every socket it accepted.
+    pub fn metrics(&self) -> ListenerMetrics {
+        listener_metrics(&self.metrics)
     }
 
     /// Accepts a new incoming connection from this listener.
@@ -1124,35 +2028,283 @@ impl UtpListener {
     /// This function will block the caller until a new uTP connection is established. When
     /// established, the corresponding `UtpSocket` and the peer's remote address will be returned.
     ///
+    /// Datagrams that don't open a connection never interrupt the wait: malformed datagrams and
+    /// `Reset` packets are dropped silently, and any other packet, which belongs to a connection
+    /// this listener doesn't know about, is answered with a `Reset`. Errors are only returned for
+    /// I/O failures.
+    ///
     /// Notice that the resulting `UtpSocket` is bound to a different local port than the public
     /// listening port (which `UtpListener` holds). This may confuse the remote peer!
     pub fn accept(&self) -> Result<(UtpSocket, SocketAddr)> {
         let mut buf = [0; BUF_SIZE];
 
-        self.socket.recv_from(&mut buf).and_then(|(nread, src)| {
-            let packet = try!(Packet::try_from(&buf[..nread]));
+        loop {
+            let (nread, src) = try!(self.socket.recv_from(&mut buf));
+            let datagram = &buf[..nread];
+            record(&self.capture, &self.socket, src, datagram, false);
+            self.metrics.add_received(nread);
+            if let Some(ref observer) = self.observer {
+                observer.on_received(datagram, src);
+            }
+
+            let packet = match PacketRef::try_from(datagram) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("dropping malformed datagram from {}: {}", src, e);
+                    self.metrics.invalid_packet();
+                    self.notify_dropped(datagram, src, DropReason::Malformed);
+                    continue;
+                }
+            };
 
-            // Ignore non-SYN packets
-            if packet.get_type() != PacketType::Syn {
-                let message = format!("Expected SYN packet, got {:?} instead", packet.get_type());
-                return Err(SocketError::Other(message).into());
+            // Reply again to retransmissions of a handshake already accepted
+            if packet.get_type() != PacketType::Reset && self.resend_handshake(&packet, src) {
+                continue;
             }
 
-            // The address of the new socket will depend on the type of the listener.
-            let inner_socket = self.socket.local_addr().and_then(|addr| match addr {
-                SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0"),
-                SocketAddr::V6(_) => UdpSocket::bind("[::]:0"),
-            });
+            match packet.get_type() {
+                PacketType::Syn if self.under_load() => {
+                    self.send_cookie(&packet, src);
+                    continue;
+                }
+                PacketType::Syn => (),
+                PacketType::Data | PacketType::State if self.check_cookie(&packet, src) => {
+                    return self.accept_stateless(&packet, datagram, src);
+                }
+                ty => {
+                    // Reset unknown connections, unless the packet is a reset itself or may have
+                    // overtaken the first packet of a stateless handshake
+                    self.metrics.unknown_packet();
+                    self.notify_dropped(datagram, src, DropReason::WrongConnectionId);
+                    if ty != PacketType::Reset && !self.may_follow_cookie(&packet, src) {
+                        self.send_reset(&packet, src);
+                    }
+                    continue;
+                }
+            }
 
-            let mut socket = try!(inner_socket.map(|s| UtpSocket::from_raw_parts(s, src)));
+            let mut socket = try!(self.new_socket(src));
 
             // Establish connection with remote peer
-            if let Ok(Some(reply)) = socket.handle_packet(&packet, src) {
-                socket.socket.send_to(reply.as_ref(), src).and(Ok((socket, src)))
-            } else {
-                Err(SocketError::Other("Reached unreachable statement".to_owned()).into())
+            let reply = match socket.handle_packet(&packet, src) {
+                Ok(Some(reply)) => reply,
+                _ => {
+                    self.metrics.handshake_rejected();
+                    continue;
+                }
+            };
+            if let Err(e) = socket.send_datagram(reply.as_ref(), src, false) {
+                self.metrics.handshake_rejected();
+                return Err(e);
             }
-        })
+
+            self.remember_handshake(&mut socket, src, packet.connection_id(), packet.seq_nr(),
+                                    reply, false);
+            self.metrics.handshake_accepted();
+            return Ok((socket, src));
+        }
+    }
+
+    /// Creates a socket for a connection with `src`, bound to a new port and sharing the
+    /// listener's hooks.
+    fn new_socket(&self, src: SocketAddr) -> Result<UtpSocket> {
+        // The address of the new socket will depend on the type of the listener.
+        let inner_socket = self.socket.local_addr().and_then(|addr| match addr {
+            SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0"),
+            SocketAddr::V6(_) => UdpSocket::bind("[::]:0"),
+        });
+
+        let mut socket = try!(inner_socket.map(|s| UtpSocket::from_raw_parts(s, src)));
+        socket.rate_limiter = self.rate_limiter.clone();
+        socket.recv_rate_limiter = self.recv_rate_limiter.clone();
+        socket.capture = self.capture.clone();
+        socket.observer = self.observer.clone();
+        socket.event_handler = self.event_handler.clone();
+        socket.metrics = Some(ConnectionMetrics::new(self.metrics.clone(), socket.state.label()));
+        socket.time_wait = self.time_wait.clone();
+        Ok(socket)
+    }
+
+    /// Remembers the reply sent by a newly accepted socket, in case the remote peer retransmits
+    /// the packet it answers. The connection is identified by the connection id and sequence
+    /// number of its SYN.
+    ///
+    /// The socket forgets the handshake as soon as it hears from the remote peer, or is dropped.
+    /// Past `MAX_HALF_OPEN` handshakes, new ones aren't remembered.
+    fn remember_handshake(&self, socket: &mut UtpSocket, src: SocketAddr, connection_id: u16,
+                          syn_seq_nr: u16, reply: Packet, confirmed: bool) {
+        let mut half_open = self.half_open.lock().unwrap();
+        if half_open.len() >= MAX_HALF_OPEN {
+            debug!("too many half-open connections, not remembering handshake of {}", src);
+            return;
+        }
+        if let Ok(inner_socket) = socket.socket.try_clone() {
+            let connection = HalfOpen {
+                socket: inner_socket,
+                seq_nr: syn_seq_nr,
+                reply,
+                accepted_at: Instant::now(),
+                confirmed,
+            };
+            half_open.insert((src, connection_id), connection);
+            socket.handshake = Some((self.half_open.clone(), (src, connection_id)));
+        }
+    }
+
+    /// Sends the reply of an accepted handshake again, in case it was lost.
+    ///
+    /// Returns whether the packet was a retransmission of the SYN, or of the first packet of a
+    /// stateless handshake.
+    fn resend_handshake(&self, packet: &PacketRef, src: SocketAddr) -> bool {
+        let mut half_open = self.half_open.lock().unwrap();
+        let timeout = Duration::from_millis(HALF_OPEN_TIMEOUT);
+        half_open.retain(|_, connection| connection.accepted_at.elapsed() < timeout);
+
+        // Packets other than SYNs carry the connection id the SYN was sent with, plus one
+        let (connection_id, syn_seq_nr) = match packet.get_type() {
+            PacketType::Syn => (packet.connection_id(), packet.seq_nr()),
+            _ => (packet.connection_id().wrapping_sub(1), packet.seq_nr().wrapping_sub(1)),
+        };
+        let connection = match half_open.get(&(src, connection_id)) {
+            Some(connection) if connection.seq_nr == syn_seq_nr => connection,
+            _ => return false,
+        };
+        let reply = connection.reply.as_ref();
+        match connection.socket.send_to(reply, src) {
+            Ok(_) => {
+                debug!("resent {:?}", connection.reply);
+                record(&self.capture, &connection.socket, src, reply, true);
+                self.metrics.add_sent(reply.len(), true);
+                if let Some(ref observer) = self.observer {
+                    observer.on_sent(reply, src, true);
+                }
+            }
+            Err(e) => debug!("Error resending handshake reply to {}: {}", src, e),
+        }
+        true
+    }
+
+    /// Returns whether SYNs should be answered statelessly, counting the handshakes whose remote
+    /// peer didn't answer yet.
+    fn under_load(&self) -> bool {
+        match self.syn_cookie_threshold {
+            Some(threshold) => {
+                let half_open = self.half_open.lock().unwrap();
+                half_open.values().filter(|connection| !connection.confirmed).count() >= threshold
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether a stateless handshake reply was sent recently enough for its remote peer
+    /// to still answer it.
+    fn cookies_pending(&self) -> bool {
+        let timeout = Duration::from_millis(HALF_OPEN_TIMEOUT);
+        match *self.last_cookie.lock().unwrap() {
+            Some(sent_at) => sent_at.elapsed() < timeout,
+            None => false,
+        }
+    }
+
+    /// Answers a SYN without creating any state, with a cookie as initial sequence number.
+    fn send_cookie(&self, syn: &PacketRef, src: SocketAddr) {
+        let now = now_microseconds();
+        let mut reply = Packet::new();
+        reply.set_type(PacketType::State);
+        reply.set_connection_id(syn.connection_id());
+        reply.set_seq_nr(self.cookies.issue(src, syn.connection_id(), syn.seq_nr()));
+        reply.set_ack_nr(syn.seq_nr());
+        reply.set_timestamp(now);
+        reply.set_timestamp_difference(abs_diff(now, syn.timestamp()));
+
+        *self.last_cookie.lock().unwrap() = Some(Instant::now());
+        self.send_to(&reply, src);
+    }
+
+    /// Returns whether a packet is the first one of the remote peer after a stateless handshake,
+    /// acknowledging a valid cookie.
+    ///
+    /// The remote peer's first packet directly follows its SYN, and carries the connection id
+    /// the SYN was sent with, plus one.
+    fn check_cookie(&self, packet: &PacketRef, src: SocketAddr) -> bool {
+        self.cookies_pending() &&
+            self.cookies.check(src, packet.connection_id().wrapping_sub(1),
+                               packet.seq_nr().wrapping_sub(1), packet.ack_nr())
+    }
+
+    /// Returns whether a packet may follow the first packet of a stateless handshake, which was
+    /// lost or reordered: its cookie is then valid for one of the sequence numbers just before.
+    fn may_follow_cookie(&self, packet: &PacketRef, src: SocketAddr) -> bool {
+        self.cookies_pending() &&
+            (2..MAX_COOKIE_REORDERING + 2).any(|distance| {
+                self.cookies.check(src, packet.connection_id().wrapping_sub(1),
+                                   packet.seq_nr().wrapping_sub(distance), packet.ack_nr())
+            })
+    }
+
+    /// Creates the socket of a stateless handshake, from the first packet of the remote peer.
+    fn accept_stateless(&self, packet: &PacketRef, datagram: &[u8], src: SocketAddr)
+                        -> Result<(UtpSocket, SocketAddr)> {
+        let connection_id = packet.connection_id().wrapping_sub(1);
+        let syn_seq_nr = packet.seq_nr().wrapping_sub(1);
+        let mut socket = try!(self.new_socket(src));
+        socket.establish(src, connection_id, syn_seq_nr, packet.ack_nr());
+
+        // Handle the packet as if the new socket received it, so that any reply tells the remote
+        // peer about the new port
+        if let Err(e) = socket.handle_datagram(datagram, src, &mut []) {
+            self.metrics.handshake_rejected();
+            return Err(e);
+        }
+
+        let reply = socket.prepare_reply(packet, PacketType::State);
+        self.remember_handshake(&mut socket, src, connection_id, syn_seq_nr, reply, true);
+
+        self.metrics.handshake_accepted();
+        Ok((socket, src))
+    }
+
+    /// Answers a packet of an unknown connection with a `Reset`, so that the remote peer gives up
+    /// on it right away instead of retransmitting until it times out.
+    fn send_reset(&self, packet: &PacketRef, src: SocketAddr) {
+        let now = now_microseconds();
+        let mut reset = Packet::new();
+        reset.set_type(PacketType::Reset);
+        reset.set_connection_id(packet.connection_id());
+        reset.set_seq_nr(rand::random());
+        reset.set_ack_nr(packet.seq_nr());
+        reset.set_timestamp(now);
+        reset.set_timestamp_difference(abs_diff(now, packet.timestamp()));
+
+        if self.send_to(&reset, src) {
+            self.metrics.reset_sent();
+        }
+    }
+
+    /// Sends a packet from the listening port, returning whether it was sent.
+    fn send_to(&self, packet: &Packet, dst: SocketAddr) -> bool {
+        match self.socket.send_to(packet.as_ref(), dst) {
+            Ok(_) => {
+                debug!("sent {:?}", packet);
+                record(&self.capture, &self.socket, dst, packet.as_ref(), true);
+                self.metrics.add_sent(packet.len(), false);
+                if let Some(ref observer) = self.observer {
+                    observer.on_sent(packet.as_ref(), dst, false);
+                }
+                true
+            }
+            Err(e) => {
+                debug!("Error sending packet to {}: {}", dst, e);
+                false
+            }
+        }
+    }
+
+    /// Notifies the observer, if any, that a datagram received from `src` was dropped.
+    fn notify_dropped(&self, datagram: &[u8], src: SocketAddr, reason: DropReason) {
+        if let Some(ref observer) = self.observer {
+            observer.on_dropped(datagram, src, reason);
+        }
     }
 
     /// Returns an iterator over the connections being received by this listener.
@@ -1168,6 +2320,36 @@ impl UtpListener {
     }
 }
 
+/// Accounts for a datagram that was just sent in a socket's counters.
+fn count_sent(stats: &mut SocketStats, metrics: &Option<ConnectionMetrics>, datagram: &[u8],
+              retransmission: bool) {
+    stats.bytes_sent += datagram.len() as u64;
+    stats.packets_sent += 1;
+    if retransmission {
+        stats.retransmissions += 1;
+    }
+    if let Some(ref metrics) = *metrics {
+        metrics.counters().add_sent(datagram.len(), retransmission);
+    }
+}
+
+/// Records a datagram exchanged between `socket` and `peer` into `capture`, if any.
+///
+/// Capture errors are logged and otherwise ignored, so they never disturb the connection.
+fn record(capture: &Option<PacketCapture>, socket: &UdpSocket, peer: SocketAddr,
+          datagram: &[u8], outgoing: bool) {
+    if let Some(ref capture) = *capture {
+        let result = socket.local_addr().and_then(|local| if outgoing {
+            capture.record(local, peer, datagram)
+        } else {
+            capture.record(peer, local, datagram)
+        });
+        if let Err(e) = result {
+            debug!("Error recording packet capture: {}", e);
+        }
+    }
+}
+
 pub struct Incoming<'a> {
     listener: &'a UtpListener,
 }
@@ -1185,9 +2367,11 @@ mod test {
     use std::thread;
     use std::net::ToSocketAddrs;
     use std::io::ErrorKind;
-    use socket::{UtpSocket, UtpListener, SocketState, BUF_SIZE, take_address};
+    use socket::{UtpSocket, UtpListener, SocketState, SentPacket, BUF_SIZE, INIT_CWND, MSS,
+                 INITIAL_CONGESTION_TIMEOUT, take_address};
     use packet::*;
     use time::now_microseconds;
+    use error::{ParseError, UtpError};
     use rand;
 
     macro_rules! iotry {
@@ -1378,7 +2562,7 @@ mod test {
         packet.set_connection_id(initial_connection_id);
 
         // Do we have a response?
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1409,7 +2593,7 @@ mod test {
         packet.set_seq_nr(old_packet.seq_nr() + 1);
         packet.set_ack_nr(old_response.seq_nr());
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1441,7 +2625,7 @@ mod test {
         packet.set_seq_nr(old_packet.seq_nr() + 1);
         packet.set_ack_nr(old_response.seq_nr());
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1462,6 +2646,39 @@ mod test {
         //}
     }
 
+    #[test]
+    fn test_duplicate_syn_after_data() {
+        use std::net::UdpSocket;
+
+        let server_addr = next_test_ip4();
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        let peer_addr = iotry!(peer.local_addr());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+        let mut syn = Packet::new();
+        syn.set_wnd_size(BUF_SIZE as u32);
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(rand::random());
+        syn.set_seq_nr(42);
+
+        let original = iotry!(socket.handle_packet(&syn.as_packet_ref(), peer_addr)).unwrap();
+        assert_eq!(original.get_type(), PacketType::State);
+
+        // The server writes before the remote peer repeats its SYN
+        iotry!(socket.send_to(b"Hello"));
+        assert!(socket.seq_nr != original.seq_nr());
+
+        // The reply is the original SYN-ACK, so that the data isn't taken for acknowledged
+        let reply = iotry!(socket.handle_packet(&syn.as_packet_ref(), peer_addr)).unwrap();
+        assert_eq!(reply.get_type(), PacketType::State);
+        assert_eq!(reply.seq_nr(), original.seq_nr());
+        assert_eq!(reply.ack_nr(), syn.seq_nr());
+        assert_eq!(reply.connection_id(), original.connection_id());
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
     #[test]
     fn test_response_to_keepalive_ack() {
         // Boilerplate test setup
@@ -1477,7 +2694,7 @@ mod test {
         packet.set_type(PacketType::Syn);
         packet.set_connection_id(initial_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1495,13 +2712,13 @@ mod test {
         packet.set_seq_nr(old_packet.seq_nr() + 1);
         packet.set_ack_nr(old_response.seq_nr());
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_none());
 
         // Send a second keepalive packet, identical to the previous one
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_none());
@@ -1525,7 +2742,7 @@ mod test {
         packet.set_type(PacketType::Syn);
         packet.set_connection_id(initial_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1539,7 +2756,7 @@ mod test {
         packet.set_type(PacketType::State);
         packet.set_connection_id(new_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1567,7 +2784,7 @@ mod test {
         packet.set_type(PacketType::Syn);
         packet.set_connection_id(initial_connection_id);
 
-        let response = socket.handle_packet(&packet, client_addr);
+        let response = socket.handle_packet(&packet.as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1595,14 +2812,14 @@ mod test {
         window.push(packet);
 
         // Send packets in reverse order
-        let response = socket.handle_packet(&window[1], client_addr);
+        let response = socket.handle_packet(&window[1].as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
         let response = response.unwrap();
         assert!(response.ack_nr() != window[1].seq_nr());
 
-        let response = socket.handle_packet(&window[0], client_addr);
+        let response = socket.handle_packet(&window[0].as_packet_ref(), client_addr);
         assert!(response.is_ok());
         let response = response.unwrap();
         assert!(response.is_some());
@@ -1662,7 +2879,7 @@ mod test {
                 assert_eq!(packet.get_type(), PacketType::Data);
                 assert_eq!(packet.seq_nr(), data_packet.seq_nr());
                 assert_eq!(packet.payload(), data_packet.payload());
-                let response = server.handle_packet(&packet, client_addr);
+                let response = server.handle_packet(&packet.as_packet_ref(), client_addr);
                 assert!(response.is_ok());
                 let response = response.unwrap();
                 assert!(response.is_some());
@@ -1672,8 +2889,8 @@ mod test {
             Err(e) => panic!("{}", e),
         }
 
-        // Receive close
-        iotry!(server.recv_from(&mut buf));
+        // Read the data, then receive close
+        while iotry!(server.recv_from(&mut buf)).0 > 0 {}
 
         assert!(child.join().is_ok());
     }
@@ -1734,118 +2951,1358 @@ mod test {
     }
 
     #[test]
-    fn test_sorted_buffer_insertion() {
-        let server_addr = next_test_ip4();
-        let mut socket = iotry!(UtpSocket::bind(server_addr));
-
-        let mut packet = Packet::new();
-        packet.set_seq_nr(1);
-
-        assert!(socket.incoming_buffer.is_empty());
-
-        socket.insert_into_buffer(packet.clone());
-        assert_eq!(socket.incoming_buffer.len(), 1);
-
-        packet.set_seq_nr(2);
-        packet.set_timestamp(128.into());
-
-        socket.insert_into_buffer(packet.clone());
-        assert_eq!(socket.incoming_buffer.len(), 2);
-        assert_eq!(socket.incoming_buffer[1].seq_nr(), 2);
-        assert_eq!(socket.incoming_buffer[1].timestamp(), 128.into());
-
-        packet.set_seq_nr(3);
-        packet.set_timestamp(256.into());
-
-        socket.insert_into_buffer(packet.clone());
-        assert_eq!(socket.incoming_buffer.len(), 3);
-        assert_eq!(socket.incoming_buffer[2].seq_nr(), 3);
-        assert_eq!(socket.incoming_buffer[2].timestamp(), 256.into());
-
-        // Replacing a packet with a more recent version doesn't work
-        packet.set_seq_nr(2);
-        packet.set_timestamp(456.into());
-
-        socket.insert_into_buffer(packet.clone());
-        assert_eq!(socket.incoming_buffer.len(), 3);
-        assert_eq!(socket.incoming_buffer[1].seq_nr(), 2);
-        assert_eq!(socket.incoming_buffer[1].timestamp(), 128.into());
-    }
-
-    #[test]
-    fn test_duplicate_packet_handling() {
-        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+    fn test_rate_limited_send() {
+        use std::time::{Duration, Instant};
+        use rate_limit::RateLimiter;
 
-        let client = iotry!(UtpSocket::bind(client_addr));
+        let server_addr = next_test_ip4();
         let mut server = iotry!(UtpSocket::bind(server_addr));
 
-        assert_eq!(server.state, SocketState::New);
-        assert_eq!(client.state, SocketState::New);
-
-        // Check proper difference in client's send connection id and receive connection id
-        assert_eq!(client.sender_connection_id, client.receiver_connection_id + 1);
+        const LEN: usize = 1024 * 10;
+        let data = (0..LEN).map(|idx| idx as u8).collect::<Vec<u8>>();
+        let to_send = data.clone();
 
+        // At 20 KB/s, sending 10 KiB takes at least 400 ms once the initial burst is spent
+        let start = Instant::now();
         let child = thread::spawn(move || {
             let mut client = iotry!(UtpSocket::connect(server_addr));
-            assert_eq!(client.state, SocketState::Connected);
-
-            let mut packet = Packet::with_payload(&[1, 2, 3]);
-            packet.set_wnd_size(BUF_SIZE as u32);
-            packet.set_connection_id(client.sender_connection_id);
-            packet.set_seq_nr(client.seq_nr);
-            packet.set_ack_nr(client.ack_nr);
-
-            // Send two copies of the packet, with different timestamps
-            for _ in 0..2 {
-                packet.set_timestamp(now_microseconds());
-                iotry!(client.socket.send_to(packet.as_ref(), server_addr));
-            }
-            client.seq_nr += 1;
-
-            // Receive one ACK
-            for _ in 0..1 {
-                let mut buf = [0; BUF_SIZE];
-                iotry!(client.socket.recv_from(&mut buf));
-            }
-
+            client.set_rate_limiter(Some(RateLimiter::new(20_000)));
+            iotry!(client.send_to(&to_send[..]));
             iotry!(client.close());
         });
 
-        let mut buf = [0u8; BUF_SIZE];
-        iotry!(server.recv(&mut buf));
-        // After establishing a new connection, the server's ids are a mirror of the client's.
-        assert_eq!(server.receiver_connection_id, server.sender_connection_id + 1);
-
-        assert_eq!(server.state, SocketState::Connected);
-
-        let expected: Vec<u8> = vec![1, 2, 3];
+        let mut buf = [0; BUF_SIZE];
         let mut received: Vec<u8> = vec![];
         loop {
             match server.recv_from(&mut buf) {
                 Ok((0, _src)) => break,
                 Ok((len, _src)) => received.extend(buf[..len].to_vec()),
-                Err(e) => panic!("{:?}", e),
+                Err(e) => panic!("{}", e),
             }
         }
-        assert_eq!(received.len(), expected.len());
-        assert_eq!(received, expected);
+        assert_eq!(received, data);
+        assert!(start.elapsed() >= Duration::from_millis(400));
 
         assert!(child.join().is_ok());
     }
 
     #[test]
-    fn test_correct_packet_loss() {
-        let server_addr = next_test_ip4();
+    fn test_rate_limited_timeout_resend() {
+        use std::time::{Duration, Instant};
+        use rate_limit::RateLimiter;
 
-        let mut server = iotry!(UtpSocket::bind(server_addr));
-        const LEN: usize = 1024 * 10;
-        let data = (0..LEN).map(|idx| idx as u8).collect::<Vec<u8>>();
-        let to_send = data.clone();
+        let (mut socket, peer) = connected_to_silent_peer();
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Data]);
 
-        let child = thread::spawn(move || {
-            let mut client = iotry!(UtpSocket::connect(server_addr));
+        // Spend the burst and then some: the resend has to wait for 200 ms worth of tokens
+        let limiter = RateLimiter::new(10_000);
+        limiter.reserve(1500 + 2000);
+        socket.set_rate_limiter(Some(limiter));
+        socket.congestion_timeout = 0;
 
-            // Send everything except the odd chunks
+        let start = Instant::now();
+        iotry!(socket.handle_receive_timeout());
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Data]);
+        assert_eq!(socket.stats().retransmissions, 1);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_rate_limited_recv() {
+        use std::net::SocketAddr;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, Instant};
+        use observer::PacketObserver;
+        use rate_limit::RateLimiter;
+
+        // Records the window advertised by every acknowledgement
+        #[derive(Default)]
+        struct Windows(Mutex<Vec<u32>>);
+
+        impl PacketObserver for Windows {
+            fn on_sent(&self, datagram: &[u8], _dst: SocketAddr, _retransmission: bool) {
+                let packet = iotry!(PacketRef::try_from(datagram));
+                if packet.get_type() == PacketType::State {
+                    self.0.lock().unwrap().push(packet.wnd_size());
+                }
+            }
+        }
+
+        let server_addr = next_test_ip4();
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+        server.set_recv_rate_limiter(Some(RateLimiter::new(20_000)));
+        let windows = Arc::new(Windows::default());
+        server.set_observer(Some(windows.clone()));
+
+        const LEN: usize = 1024 * 10;
+        let data = (0..LEN).map(|idx| idx as u8).collect::<Vec<u8>>();
+        let to_send = data.clone();
+
+        // At 20 KB/s, receiving 10 KiB takes at least 400 ms once the initial burst is spent
+        let start = Instant::now();
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(&to_send[..]));
+            iotry!(client.close());
+        });
+
+        let mut buf = [0; BUF_SIZE];
+        let mut received: Vec<u8> = vec![];
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        // Throttling must not lose any data, nor close the window
+        assert_eq!(received, data);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        let windows = windows.0.lock().unwrap();
+        assert!(!windows.is_empty());
+        assert!(windows.iter().all(|&wnd_size| wnd_size >= MSS));
+
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn test_listener_metrics() {
+        use std::net::UdpSocket;
+
+        let server_addr = next_test_ip4();
+        let listener = iotry!(UtpListener::bind(server_addr));
+        let metrics = listener.metrics();
+
+        // Garbage is counted as an invalid packet, and skipped
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.send_to(&[1, 2, 3], server_addr));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(&[1, 2, 3, 4]));
+            iotry!(client.close());
+        });
+
+        let (mut server, _src) = iotry!(listener.accept());
+        let mut buf = [0; BUF_SIZE];
+        while iotry!(server.recv_from(&mut buf)).0 > 0 {}
+        assert!(child.join().is_ok());
+
+        let out = metrics.render_prometheus();
+        assert!(out.contains("utp_handshakes_accepted_total 1\n"));
+        assert!(out.contains("utp_invalid_packets_total 1\n"));
+        assert!(out.contains("utp_connections{state=\"closed\"} 1\n"));
+        assert!(out.contains("utp_connections_active 0\n"));
+        assert!(!out.contains("utp_received_bytes_total 0\n"));
+        assert!(!out.contains("utp_sent_bytes_total 0\n"));
+
+        // Dropped sockets are no longer counted
+        drop(server);
+        let out = metrics.render_prometheus();
+        assert!(out.contains("utp_connections{state=\"closed\"} 0\n"));
+    }
+
+    #[test]
+    fn test_listener_resets_unknown_connections() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let listener = iotry!(UtpListener::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+
+        let mut data = Packet::with_payload(&[1, 2, 3]);
+        data.set_connection_id(1234);
+        data.set_seq_nr(42);
+        data.set_timestamp(now_microseconds());
+        let mut reset = data.clone();
+        reset.set_type(PacketType::Reset);
+
+        // None of these interrupt the wait for a connection
+        iotry!(peer.send_to(&[1, 2, 3], server_addr));
+        iotry!(peer.send_to(data.as_ref(), server_addr));
+        iotry!(peer.send_to(reset.as_ref(), server_addr));
+        let child = thread::spawn(move || {
+            iotry!(UtpSocket::connect(server_addr));
+        });
+        iotry!(listener.accept());
+        assert!(child.join().is_ok());
+
+        // Only the data packet is answered
+        let mut buf = [0; BUF_SIZE];
+        let (len, _) = iotry!(peer.recv_from(&mut buf));
+        let packet = iotry!(Packet::try_from(&buf[..len]));
+        assert_eq!(packet.get_type(), PacketType::Reset);
+        assert_eq!(packet.connection_id(), data.connection_id());
+        assert_eq!(packet.ack_nr(), data.seq_nr());
+        assert!(drain_packet_types(&peer).is_empty());
+    }
+
+    #[test]
+    fn test_listener_duplicate_syn() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let listener = iotry!(UtpListener::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+
+        let mut syn = Packet::new();
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(1234);
+        syn.set_seq_nr(42);
+        syn.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(syn.as_ref(), server_addr));
+        let (mut server, _src) = iotry!(listener.accept());
+        let accepted_addr = iotry!(server.local_addr());
+
+        // The reply is lost, and the SYN retransmitted: no new socket is created for it
+        iotry!(peer.send_to(syn.as_ref(), server_addr));
+        let child = thread::spawn(move || {
+            iotry!(UtpSocket::connect(server_addr));
+        });
+        let (other, _src) = iotry!(listener.accept());
+        assert!(child.join().is_ok());
+        assert!(iotry!(other.local_addr()) != accepted_addr);
+
+        // Both replies are the same, and come from the accepted socket
+        let mut buf = [0; BUF_SIZE];
+        let mut replies = vec![];
+        for _ in 0..2 {
+            let (len, src) = iotry!(peer.recv_from(&mut buf));
+            assert_eq!(src.port(), accepted_addr.port());
+            replies.push(iotry!(Packet::try_from(&buf[..len])));
+        }
+        assert_eq!(replies[0].get_type(), PacketType::State);
+        assert_eq!(replies[1].get_type(), PacketType::State);
+        assert_eq!(replies[0].seq_nr(), replies[1].seq_nr());
+        assert_eq!(replies[0].ack_nr(), syn.seq_nr());
+        assert_eq!(replies[1].ack_nr(), syn.seq_nr());
+
+        // The listener remembers both handshakes
+        assert_eq!(listener.half_open.lock().unwrap().len(), 2);
+
+        // A SYN retransmitted to the accepted socket is answered the same way
+        iotry!(peer.send_to(syn.as_ref(), accepted_addr));
+        iotry!(server.socket.set_read_timeout(Some(Duration::from_millis(100))));
+        let _ = server.recv(&mut buf);
+        let (len, _) = iotry!(peer.recv_from(&mut buf));
+        let reply = iotry!(Packet::try_from(&buf[..len]));
+        assert_eq!(reply.get_type(), PacketType::State);
+        assert_eq!(reply.seq_nr(), replies[0].seq_nr());
+        assert!(drain_packet_types(&peer).is_empty());
+
+        // Handshakes are forgotten once the remote peer reaches the accepted socket, or once the
+        // socket is dropped
+        assert_eq!(listener.half_open.lock().unwrap().len(), 1);
+        drop(other);
+        assert!(listener.half_open.lock().unwrap().is_empty());
+
+        // Mark socket as closed
+        server.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_listener_syn_cookies() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let mut listener = iotry!(UtpListener::bind(server_addr));
+        listener.set_syn_cookies(Some(0));
+        let listener_addr = iotry!(listener.local_addr());
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+
+        // A SYN is answered from the listening port, without creating a socket
+        let mut syn = Packet::new();
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(1234);
+        syn.set_seq_nr(42);
+        syn.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(syn.as_ref(), server_addr));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            assert_eq!(client.connected_to, listener_addr);
+            iotry!(client.send_to(&[1, 2, 3]));
+
+            // The connection moves to the port of the accepted socket
+            let mut buf = [0; BUF_SIZE];
+            let (len, _src) = iotry!(client.recv_from(&mut buf));
+            assert_eq!(&buf[..len], &[4, 5, 6]);
+            assert!(client.connected_to != listener_addr);
+            iotry!(client.close());
+        });
+
+        // Only the client, which echoed its cookie back, gets a socket
+        let (mut server, src) = iotry!(listener.accept());
+        assert!(src != iotry!(peer.local_addr()));
+        let mut buf = [0; BUF_SIZE];
+        let (len, _src) = iotry!(server.recv_from(&mut buf));
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        iotry!(server.send_to(&[4, 5, 6]));
+        while iotry!(server.recv_from(&mut buf)).0 > 0 {}
+        assert!(child.join().is_ok());
+
+        let (len, src) = iotry!(peer.recv_from(&mut buf));
+        let reply = iotry!(Packet::try_from(&buf[..len]));
+        assert_eq!(src.port(), listener_addr.port());
+        assert_eq!(reply.get_type(), PacketType::State);
+        assert_eq!(reply.connection_id(), syn.connection_id());
+        assert_eq!(reply.ack_nr(), syn.seq_nr());
+        assert!(listener.metrics().render_prometheus().contains("utp_handshakes_accepted_total 1\n"));
+    }
+
+    #[test]
+    fn test_syn_cookie_threshold_counts_pending_handshakes() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let mut listener = iotry!(UtpListener::bind(server_addr));
+        listener.set_syn_cookies(Some(1));
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        assert!(!listener.under_load());
+
+        // A handshake waiting for the remote peer counts towards the threshold
+        let mut syn = Packet::new();
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(1234);
+        syn.set_seq_nr(42);
+        syn.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(syn.as_ref(), server_addr));
+        let (first, _src) = iotry!(listener.accept());
+        assert!(listener.under_load());
+
+        // A stateless handshake, confirmed by the remote peer, doesn't
+        syn.set_connection_id(5678);
+        listener.send_cookie(&syn.as_packet_ref(), iotry!(peer.local_addr()));
+        let cookie = listener.cookies.issue(iotry!(peer.local_addr()), 5678, 42);
+        let mut data = Packet::with_payload(&[1, 2, 3]);
+        data.set_connection_id(syn.connection_id() + 1);
+        data.set_seq_nr(syn.seq_nr() + 1);
+        data.set_ack_nr(cookie);
+        data.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(data.as_ref(), server_addr));
+        let (mut second, _src) = iotry!(listener.accept());
+
+        drop(first);
+        assert_eq!(listener.half_open.lock().unwrap().len(), 1);
+        assert!(!listener.under_load());
+
+        // Mark socket as closed
+        second.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_listener_resets_unknown_packets_while_cookies_pending() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let mut listener = iotry!(UtpListener::bind(server_addr));
+        listener.set_syn_cookies(Some(0));
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        let peer_addr = iotry!(peer.local_addr());
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+
+        let mut syn = Packet::new();
+        syn.set_type(PacketType::Syn);
+        syn.set_connection_id(1234);
+        syn.set_seq_nr(42);
+        syn.set_timestamp(now_microseconds());
+        listener.send_cookie(&syn.as_packet_ref(), peer_addr);
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::State]);
+        let cookie = listener.cookies.issue(peer_addr, 1234, 42);
+
+        // A packet of an unknown connection is reset, even though a cookie was sent recently
+        let mut stray = Packet::with_payload(&[1, 2, 3]);
+        stray.set_connection_id(999);
+        stray.set_seq_nr(7);
+        stray.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(stray.as_ref(), server_addr));
+
+        // A packet overtaking the cookie echo isn't
+        let mut data = Packet::with_payload(&[4, 5, 6]);
+        data.set_connection_id(syn.connection_id() + 1);
+        data.set_seq_nr(syn.seq_nr() + 2);
+        data.set_ack_nr(cookie);
+        data.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(data.as_ref(), server_addr));
+
+        data.set_seq_nr(syn.seq_nr() + 1);
+        iotry!(peer.send_to(data.as_ref(), server_addr));
+        let (mut server, _src) = iotry!(listener.accept());
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Reset, PacketType::State]);
+
+        // Neither is counted as a rejected handshake
+        let out = listener.metrics().render_prometheus();
+        assert!(out.contains("utp_unknown_packets_total 2\n"));
+        assert!(out.contains("utp_handshakes_rejected_total 0\n"));
+        assert!(out.contains("utp_resets_sent_total 1\n"));
+
+        // Mark socket as closed
+        server.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_event_handler() {
+        use std::net::SocketAddr;
+        use std::sync::{Arc, Mutex};
+        use events::{CloseReason, EventHandler};
+
+        #[derive(Default)]
+        struct Recorder {
+            events: Mutex<Vec<String>>,
+        }
+
+        impl EventHandler for Recorder {
+            fn on_connected(&self, _peer: SocketAddr) {
+                self.events.lock().unwrap().push("connected".to_owned());
+            }
+
+            fn on_fin_received(&self, _peer: SocketAddr) {
+                self.events.lock().unwrap().push("fin".to_owned());
+            }
+
+            fn on_closed(&self, _peer: SocketAddr, reason: CloseReason) {
+                self.events.lock().unwrap().push(format!("closed {:?}", reason));
+            }
+        }
+
+        let server_addr = next_test_ip4();
+        let mut listener = iotry!(UtpListener::bind(server_addr));
+        let handler = Arc::new(Recorder::default());
+        listener.set_event_handler(Some(handler.clone()));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(&[1, 2, 3, 4]));
+            iotry!(client.close());
+        });
+
+        let (mut server, _src) = iotry!(listener.accept());
+        let mut buf = [0; BUF_SIZE];
+        while iotry!(server.recv_from(&mut buf)).0 > 0 {}
+        assert!(child.join().is_ok());
+
+        assert_eq!(*handler.events.lock().unwrap(), vec!["connected", "fin", "closed Graceful"]);
+    }
+
+    #[test]
+    fn test_capture_records_every_datagram() {
+        use std::fs::{self, File};
+        use std::io::Read;
+        use std::env;
+        use capture::PacketCapture;
+
+        let server_addr = next_test_ip4();
+        let path = env::temp_dir().join(format!("utp-capture-{}.pcap", server_addr.1));
+        let mut listener = iotry!(UtpListener::bind(server_addr));
+        listener.set_capture(Some(iotry!(PacketCapture::create(&path))));
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            iotry!(client.send_to(&[1, 2, 3, 4]));
+            iotry!(client.close());
+        });
+
+        let (mut server, _src) = iotry!(listener.accept());
+        let mut buf = [0; BUF_SIZE];
+        while iotry!(server.recv_from(&mut buf)).0 > 0 {}
+        assert!(child.join().is_ok());
+
+        // Dropping the last handle to the capture flushes it
+        drop(server);
+        drop(listener);
+
+        let mut bytes = Vec::new();
+        iotry!(iotry!(File::open(&path)).read_to_end(&mut bytes));
+        let _ = fs::remove_file(&path);
+
+        // Walk the records, extracting the uTP packet from each synthesized IPv4 and UDP packet
+        let mut types = Vec::new();
+        let mut offset = 24;
+        while offset < bytes.len() {
+            let len = bytes[offset + 8] as usize | (bytes[offset + 9] as usize) << 8;
+            let record = &bytes[offset + 16..offset + 16 + len];
+            let packet = iotry!(PacketRef::try_from(&record[20 + 8..]));
+            types.push(packet.get_type());
+            offset += 16 + len;
+        }
+        assert_eq!(offset, bytes.len());
+
+        // The handshake, the data and the closing exchange
+        assert_eq!(types[0], PacketType::Syn);
+        assert_eq!(types[1], PacketType::State);
+        assert!(types.contains(&PacketType::Data));
+        assert!(types.contains(&PacketType::Fin));
+    }
+
+    #[test]
+    fn test_observer_notifications() {
+        use std::net::{SocketAddr, UdpSocket};
+        use std::sync::{Arc, Mutex};
+        use observer::{DropReason, PacketObserver};
+
+        #[derive(Default)]
+        struct Recorder {
+            events: Mutex<Vec<(&'static str, Option<DropReason>)>>,
+        }
+
+        impl PacketObserver for Recorder {
+            fn on_sent(&self, _datagram: &[u8], _dst: SocketAddr, retransmission: bool) {
+                let event = if retransmission { "resent" } else { "sent" };
+                self.events.lock().unwrap().push((event, None));
+            }
+
+            fn on_received(&self, _datagram: &[u8], _src: SocketAddr) {
+                self.events.lock().unwrap().push(("received", None));
+            }
+
+            fn on_dropped(&self, _datagram: &[u8], _src: SocketAddr, reason: DropReason) {
+                self.events.lock().unwrap().push(("dropped", Some(reason)));
+            }
+        }
+
+        let (server_addr, peer_addr) = (next_test_ip4(), next_test_ip4());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(peer_addr));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        let observer = Arc::new(Recorder::default());
+        socket.set_observer(Some(observer.clone()));
+
+        let mut packet = Packet::with_payload(&[1, 2, 3]);
+        packet.set_connection_id(socket.receiver_connection_id);
+        packet.set_seq_nr(socket.ack_nr + 1);
+        let mut wrong_id = packet.clone();
+        wrong_id.set_connection_id(socket.receiver_connection_id.wrapping_add(10));
+
+        // A malformed datagram, a data packet, its duplicate and a packet for another connection
+        for datagram in &[&[1, 2, 3][..], packet.as_ref(), packet.as_ref(), wrong_id.as_ref()] {
+            iotry!(peer.send_to(datagram, server_addr));
+            iotry!(socket.recv(&mut []));
+        }
+
+        // A first transmission and a retransmission
+        iotry!(socket.send_to(&[4, 5, 6]));
+        let seq_nr = socket.seq_nr - 1;
+        socket.resend_lost_packet(seq_nr);
+
+        let events = observer.events.lock().unwrap();
+        assert_eq!(*events, vec![("received", None),
+                                 ("dropped", Some(DropReason::Malformed)),
+                                 ("received", None),
+                                 ("sent", None),
+                                 ("received", None),
+                                 ("dropped", Some(DropReason::Duplicate)),
+                                 ("sent", None),
+                                 ("received", None),
+                                 ("dropped", Some(DropReason::WrongConnectionId)),
+                                 ("sent", None),
+                                 ("sent", None),
+                                 ("resent", None)]);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_stats() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let (server_addr, peer_addr) = (next_test_ip4(), next_test_ip4());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(peer_addr));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+
+        // A data packet and its duplicate
+        let mut packet = Packet::with_payload(&[1, 2, 3]);
+        packet.set_connection_id(socket.receiver_connection_id);
+        packet.set_seq_nr(socket.ack_nr + 1);
+        packet.set_wnd_size(10_000);
+        for _ in 0..2 {
+            iotry!(peer.send_to(packet.as_ref(), server_addr));
+            iotry!(socket.recv(&mut []));
+        }
+
+        // A data packet, resent once
+        iotry!(socket.send_to(&[4, 5, 6]));
+        let seq_nr = socket.seq_nr - 1;
+        socket.resend_lost_packet(seq_nr);
+
+        let stats = socket.stats();
+        assert_eq!(stats.packets_received, 2);
+        assert_eq!(stats.bytes_received, 2 * packet.len() as u64);
+        assert_eq!(stats.duplicate_packets, 1);
+        // Two acknowledgements, a data packet and its retransmission
+        assert_eq!(stats.packets_sent, 4);
+        assert_eq!(stats.bytes_sent, 2 * HEADER_SIZE as u64 + 2 * (HEADER_SIZE as u64 + 3));
+        assert_eq!(stats.retransmissions, 1);
+        assert_eq!(stats.fast_retransmits, 1);
+        assert_eq!(stats.timeouts, 0);
+        assert_eq!(stats.sacks_received, 0);
+        assert_eq!(stats.rto, Duration::from_millis(INITIAL_CONGESTION_TIMEOUT));
+        assert_eq!(stats.cwnd, socket.cwnd);
+        assert_eq!(stats.flight_size, HEADER_SIZE as u32 + 3);
+        assert_eq!(stats.peer_window, 10_000);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_pacing_interval() {
+        use std::time::Duration;
+
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+        // Disabled by default
+        socket.rtt = 100;
+        assert_eq!(socket.pacing_interval(), None);
+
+        // No estimate of the round-trip time yet
+        socket.set_pacing(true);
+        socket.rtt = 0;
+        assert_eq!(socket.pacing_interval(), None);
+
+        // Ten packets over 100 ms
+        socket.rtt = 100;
+        socket.cwnd = 10 * MSS;
+        assert_eq!(socket.pacing_interval(), Some(Duration::from_millis(10)));
+
+        // A window smaller than a packet sends one packet per round-trip
+        socket.cwnd = MSS / 2;
+        assert_eq!(socket.pacing_interval(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_karn_algorithm() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        socket.state = SocketState::Connected;
+
+        let mut packet = Packet::with_payload(&[1, 2, 3]);
+        packet.set_seq_nr(1);
+        packet.set_timestamp(now_microseconds());
+        let mut sent = SentPacket::new(packet);
+        sent.transmissions = 2;
+        socket.curr_window += sent.packet.len() as u32;
+        socket.send_window.push_back(sent.packet.seq_nr(), sent);
+        socket.seq_nr = 3;
+
+        let mut ack = Packet::new();
+        ack.set_type(PacketType::State);
+        ack.set_ack_nr(1);
+        socket.handle_state_packet(&ack.as_packet_ref());
+
+        // The acknowledgement of a retransmitted packet doesn't produce samples
+        assert!(socket.send_window.is_empty());
+        assert!(socket.base_delays.is_empty());
+        assert!(socket.current_delays.is_empty());
+        assert_eq!(socket.rtt, 0);
+
+        let mut packet = Packet::with_payload(&[4, 5, 6]);
+        packet.set_seq_nr(2);
+        packet.set_timestamp(now_microseconds());
+        socket.curr_window += packet.len() as u32;
+        socket.send_window.push_back(packet.seq_nr(), SentPacket::new(packet));
+
+        ack.set_ack_nr(2);
+        socket.handle_state_packet(&ack.as_packet_ref());
+
+        // A packet sent only once does
+        assert!(socket.send_window.is_empty());
+        assert_eq!(socket.base_delays.len(), 1);
+        assert_eq!(socket.current_delays.len(), 1);
+    }
+
+    #[test]
+    fn test_timeout_resends_overdue_packets() {
+        use std::net::UdpSocket;
+        use std::time::{Duration, Instant};
+
+        let (server_addr, peer_addr) = (next_test_ip4(), next_test_ip4());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(peer_addr));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        socket.congestion_timeout = 500;
+
+        // Two packets sent long ago, one sent just now
+        let long_ago = Instant::now() - Duration::from_secs(1);
+        for seq_nr in 1..4 {
+            let mut packet = Packet::with_payload(&[seq_nr as u8]);
+            packet.set_seq_nr(seq_nr);
+            let mut sent = SentPacket::new(packet);
+            if seq_nr < 3 {
+                sent.last_sent = long_ago;
+            }
+            socket.send_window.push_back(sent.packet.seq_nr(), sent);
+        }
+
+        iotry!(socket.handle_receive_timeout());
+
+        let mut buf = [0; BUF_SIZE];
+        let mut resent = vec![];
+        while let Ok((len, _src)) = peer.recv_from(&mut buf) {
+            resent.push(iotry!(Packet::try_from(&buf[..len])).seq_nr());
+        }
+        assert_eq!(resent, vec![1, 2]);
+
+        let transmissions = socket.send_window.iter().map(|p| p.transmissions).collect::<Vec<_>>();
+        assert_eq!(transmissions, vec![2, 2, 1]);
+        assert_eq!(socket.congestion_timeout, 1000);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    /// Returns the types of the packets a fake peer receives until it's silent for 100 ms.
+    fn drain_packet_types(peer: &::std::net::UdpSocket) -> Vec<PacketType> {
+        let mut buf = [0; BUF_SIZE];
+        let mut types = Vec::new();
+        while let Ok((len, _src)) = peer.recv_from(&mut buf) {
+            types.push(iotry!(PacketRef::try_from(&buf[..len])).get_type());
+        }
+        types
+    }
+
+    /// Returns a socket connected to a fake, silent peer, with some unacknowledged data.
+    fn connected_to_silent_peer() -> (UtpSocket, ::std::net::UdpSocket) {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let mut socket = iotry!(UtpSocket::bind(next_test_ip4()));
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        socket.remote_wnd_size = BUF_SIZE as u32 * 100;
+        iotry!(socket.send_to(&[1, 2, 3]));
+        (socket, peer)
+    }
+
+    #[test]
+    fn test_drop_closes_in_background() {
+        use std::time::{Duration, Instant};
+
+        let (mut socket, peer) = connected_to_silent_peer();
+        socket.congestion_timeout = 50;
+        socket.max_retransmission_retries = 1;
+        let start = Instant::now();
+        drop(socket);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // The background thread keeps trying to deliver the data
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Data, PacketType::Data]);
+    }
+
+    #[test]
+    fn test_drop_with_linger_timeout() {
+        use std::time::{Duration, Instant};
+
+        let (mut socket, peer) = connected_to_silent_peer();
+        socket.set_linger(Some(Duration::from_millis(200)));
+        let start = Instant::now();
+        drop(socket);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(1000));
+
+        let types = drain_packet_types(&peer);
+        assert_eq!(types.last(), Some(&PacketType::Reset));
+    }
+
+    #[test]
+    fn test_drop_with_zero_linger_aborts() {
+        use std::time::{Duration, Instant};
+
+        let (mut socket, peer) = connected_to_silent_peer();
+        socket.set_linger(Some(Duration::from_secs(0)));
+        let start = Instant::now();
+        drop(socket);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // The data packet, then the reset
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Data, PacketType::Reset]);
+    }
+
+    #[test]
+    fn test_abort() {
+        let (mut socket, peer) = connected_to_silent_peer();
+        let connection_id = socket.sender_connection_id;
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Data]);
+
+        iotry!(socket.abort());
+        assert!(socket.send_window.is_empty());
+        assert!(socket.unsent_queue.is_empty());
+
+        let mut buf = [0; BUF_SIZE];
+        let (len, _) = iotry!(peer.recv_from(&mut buf));
+        let packet = iotry!(Packet::try_from(&buf[..len]));
+        assert_eq!(packet.get_type(), PacketType::Reset);
+        assert_eq!(packet.connection_id(), connection_id);
+
+        // Every later operation fails
+        let check = |result: ::std::io::Result<()>| match result {
+            Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => (),
+            other => panic!("expected ConnectionAborted, got {:?}", other),
+        };
+        check(socket.send_to(&[1]).map(|_| ()));
+        check(socket.recv_from(&mut buf).map(|_| ()));
+        check(socket.flush());
+        check(socket.close());
+
+        // Nothing is sent when the socket is dropped
+        drop(socket);
+        assert!(drain_packet_types(&peer).is_empty());
+    }
+
+    #[test]
+    fn test_full_incoming_buffer_is_not_acknowledged() {
+        use std::cmp::min;
+        use std::net::UdpSocket;
+        use std::time::Duration;
+        use socket::{INCOMING_BUFFER_SIZE, WINDOW_SIZE};
+
+        let mut socket = iotry!(UtpSocket::bind(next_test_ip4()));
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        let peer_addr = iotry!(peer.local_addr());
+        socket.connected_to = peer_addr;
+        socket.state = SocketState::Connected;
+        socket.incoming_buffer.reset(socket.ack_nr.wrapping_add(1));
+
+        let mut packet = Packet::with_payload(&[1, 2, 3]);
+        packet.set_connection_id(socket.receiver_connection_id);
+        let first = socket.ack_nr.wrapping_add(1);
+        let mut buf = [0; BUF_SIZE];
+
+        // Every packet is acknowledged while the application doesn't read, and the advertised
+        // window shrinks accordingly
+        for i in 0..INCOMING_BUFFER_SIZE {
+            packet.set_seq_nr(first.wrapping_add(i as u16));
+            iotry!(socket.handle_datagram(packet.as_ref(), peer_addr, &mut []));
+            let (len, _) = iotry!(peer.recv_from(&mut buf));
+            let reply = iotry!(Packet::try_from(&buf[..len]));
+            assert_eq!(reply.ack_nr(), packet.seq_nr());
+            let room = (INCOMING_BUFFER_SIZE - i - 1) * (MSS as usize - HEADER_SIZE);
+            assert_eq!(reply.wnd_size(), min(room, WINDOW_SIZE as usize) as u32);
+        }
+
+        // The buffer is full: the next packet is neither acknowledged nor stored
+        let ack_nr = socket.ack_nr;
+        packet.set_seq_nr(first.wrapping_add(INCOMING_BUFFER_SIZE as u16));
+        iotry!(socket.handle_datagram(packet.as_ref(), peer_addr, &mut []));
+        assert_eq!(socket.ack_nr, ack_nr);
+        assert!(drain_packet_types(&peer).is_empty());
+
+        // Reading a packet makes room for its retransmission
+        assert_eq!(socket.flush_incoming_buffer(&mut buf[..3]), 3);
+        iotry!(socket.handle_datagram(packet.as_ref(), peer_addr, &mut []));
+        assert_eq!(socket.ack_nr, packet.seq_nr());
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::State]);
+    }
+
+    #[test]
+    fn test_time_wait_answers_late_packets() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+        use time_wait::TimeWait;
+
+        let mut socket = iotry!(UtpSocket::bind(next_test_ip4()));
+        let socket_addr = iotry!(socket.local_addr());
+        let peer = iotry!(UdpSocket::bind(next_test_ip4()));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        socket.time_wait = Some(TimeWait::new(Duration::from_millis(500)));
+
+        let mut fin = Packet::new();
+        fin.set_type(PacketType::Fin);
+        fin.set_connection_id(socket.receiver_connection_id);
+        fin.set_seq_nr(socket.ack_nr + 1);
+        fin.set_ack_nr(socket.seq_nr - 1);
+        fin.set_timestamp(now_microseconds());
+        iotry!(peer.send_to(fin.as_ref(), socket_addr));
+
+        let mut buf = [0; BUF_SIZE];
+        assert_eq!(iotry!(socket.recv_from(&mut buf)).0, 0);
+        assert_eq!(socket.state, SocketState::Closed);
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::State]);
+        let connection_id = socket.sender_connection_id;
+        drop(socket);
+
+        // The acknowledgement was lost, so the remote peer retransmits its FIN
+        iotry!(peer.send_to(fin.as_ref(), socket_addr));
+        let (len, _) = iotry!(peer.recv_from(&mut buf));
+        let reply = iotry!(Packet::try_from(&buf[..len]));
+        assert_eq!(reply.get_type(), PacketType::State);
+        assert_eq!(reply.connection_id(), connection_id);
+        assert_eq!(reply.ack_nr(), fin.seq_nr());
+
+        // Late data is reset
+        let mut data = fin.clone();
+        data.set_type(PacketType::Data);
+        iotry!(peer.send_to(data.as_ref(), socket_addr));
+        let (len, _) = iotry!(peer.recv_from(&mut buf));
+        let reply = iotry!(Packet::try_from(&buf[..len]));
+        assert_eq!(reply.get_type(), PacketType::Reset);
+        assert_eq!(reply.connection_id(), connection_id);
+
+        // Packets of other connections are ignored
+        let mut other = fin.clone();
+        other.set_connection_id(fin.connection_id().wrapping_add(7));
+        iotry!(peer.send_to(other.as_ref(), socket_addr));
+        assert!(drain_packet_types(&peer).is_empty());
+    }
+
+    #[test]
+    fn test_send_buffer_packet_limit() {
+        use std::usize;
+        use socket::MAX_BUFFERED_PACKETS;
+
+        let (mut socket, _peer) = connected_to_silent_peer();
+        socket.set_send_buffer_size(usize::MAX);
+        assert_eq!(socket.send_buffer_size, MAX_BUFFERED_PACKETS * MSS as usize);
+        socket.set_nonblocking(true);
+
+        // Tiny writes fill the buffer with packets long before it's full of bytes
+        loop {
+            match socket.send_to(&[1]) {
+                Ok(1) => (),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                other => panic!("expected a write or WouldBlock, got {:?}", other),
+            }
+        }
+        assert_eq!(socket.send_window.len() + socket.unsent_queue.len(), MAX_BUFFERED_PACKETS);
+        assert_eq!(socket.buffered_packets(), MAX_BUFFERED_PACKETS);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_nonblocking_write_with_full_send_buffer() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let (server_addr, peer_addr) = (next_test_ip4(), next_test_ip4());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(peer_addr));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        socket.remote_wnd_size = BUF_SIZE as u32 * 100;
+        socket.cwnd = 2 * MSS;
+        socket.set_send_buffer_size(4 * MSS as usize);
+        socket.set_nonblocking(true);
+
+        // Only four packets fit in the send buffer, and only two in the congestion window
+        let data = [0; 10_000];
+        let payload_len = MSS as usize - HEADER_SIZE;
+        let first_seq_nr = socket.seq_nr;
+        assert_eq!(iotry!(socket.send_to(&data)), 4 * payload_len);
+        assert_eq!(socket.send_window.len(), 2);
+        assert_eq!(socket.unsent_queue.len(), 2);
+
+        match socket.send_to(&data) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            other => panic!("expected WouldBlock, got {:?}", other),
+        }
+
+        // Acknowledging the packets in flight makes room for more data
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::State);
+        packet.set_connection_id(socket.receiver_connection_id);
+        packet.set_ack_nr(first_seq_nr + 1);
+        packet.set_wnd_size(BUF_SIZE as u32 * 100);
+        iotry!(peer.send_to(packet.as_ref(), server_addr));
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(iotry!(socket.send_to(&data)), 2 * payload_len);
+        assert_eq!(socket.buffered_bytes(), 4 * MSS as usize);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_selective_ack_loss_detection() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let (server_addr, peer_addr) = (next_test_ip4(), next_test_ip4());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(peer_addr));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        socket.rtt = 1000;
+        socket.remote_wnd_size = BUF_SIZE as u32 * 10;
+
+        // Packets 1 to 7 are in flight
+        for seq_nr in 1..8 {
+            let mut packet = Packet::with_payload(&[seq_nr as u8]);
+            packet.set_seq_nr(seq_nr);
+            packet.set_timestamp(now_microseconds());
+            socket.curr_window += packet.len() as u32;
+            let mut sent = SentPacket::new(packet);
+            sent.last_sent -= Duration::from_secs(2);
+            socket.send_window.push_back(sent.packet.seq_nr(), sent);
+        }
+        socket.seq_nr = 8;
+
+        // Acknowledge packet 1 and selectively acknowledge packets 3, 5, 6 and 7
+        let mut ack = Packet::new();
+        ack.set_type(PacketType::State);
+        ack.set_ack_nr(1);
+        ack.set_sack(vec![0b11101, 0, 0, 0]);
+        socket.handle_state_packet(&ack.as_packet_ref());
+
+        // Only the missing packets remain, and both had at least three packets acknowledged after
+        // them, so they were resent
+        let remaining = socket.send_window.iter().map(|p| p.packet.seq_nr()).collect::<Vec<_>>();
+        assert_eq!(remaining, vec![2, 4]);
+        let in_flight = socket.send_window.iter().fold(0, |acc, p| acc + p.packet.len());
+        assert_eq!(socket.curr_window, in_flight as u32);
+
+        let mut buf = [0; BUF_SIZE];
+        let mut resent = vec![];
+        while let Ok((len, _src)) = peer.recv_from(&mut buf) {
+            resent.push(iotry!(Packet::try_from(&buf[..len])).seq_nr());
+        }
+        assert_eq!(resent, vec![2, 4]);
+
+        // The same acknowledgement within one round-trip time doesn't trigger another resend
+        socket.handle_state_packet(&ack.as_packet_ref());
+        assert!(peer.recv_from(&mut buf).is_err());
+
+        // A cumulative acknowledgement past selectively acknowledged packets clears the window
+        let mut ack = Packet::new();
+        ack.set_type(PacketType::State);
+        ack.set_ack_nr(7);
+        socket.handle_state_packet(&ack.as_packet_ref());
+        assert!(socket.send_window.is_empty());
+        assert_eq!(socket.curr_window, 0);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_selective_ack_loss_without_rtt_sample() {
+        use std::net::UdpSocket;
+        use std::time::Duration;
+
+        let (server_addr, peer_addr) = (next_test_ip4(), next_test_ip4());
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        let peer = iotry!(UdpSocket::bind(peer_addr));
+        iotry!(peer.set_read_timeout(Some(Duration::from_millis(100))));
+        socket.connected_to = iotry!(peer.local_addr());
+        socket.state = SocketState::Connected;
+        socket.remote_wnd_size = BUF_SIZE as u32 * 10;
+        socket.cwnd = 100 * MSS;
+        assert_eq!(socket.rtt, 0);
+
+        // Packets 1 to 6 are in flight, and were sent before the retransmission timeout
+        for seq_nr in 1..7 {
+            let mut packet = Packet::with_payload(&[seq_nr as u8]);
+            packet.set_seq_nr(seq_nr);
+            socket.curr_window += packet.len() as u32;
+            let mut sent = SentPacket::new(packet);
+            sent.last_sent -= Duration::from_millis(2 * INITIAL_CONGESTION_TIMEOUT);
+            socket.send_window.push_back(sent.packet.seq_nr(), sent);
+        }
+        socket.seq_nr = 7;
+
+        // Packet 1 is lost, and reported as such by every following acknowledgement, none of which
+        // yields a round-trip time sample
+        let mut ack = Packet::new();
+        ack.set_type(PacketType::State);
+        ack.set_ack_nr(0);
+        ack.set_sack(vec![0b11111, 0, 0, 0]);
+        for _ in 0..2 {
+            socket.handle_state_packet(&ack.as_packet_ref());
+        }
+        assert_eq!(socket.rtt, 0);
+
+        // It was only resent once, and the congestion window only halved once
+        assert_eq!(drain_packet_types(&peer), vec![PacketType::Data]);
+        assert_eq!(socket.cwnd, 50 * MSS);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_selective_ack_ignores_recent_gaps() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        socket.state = SocketState::Connected;
+
+        for seq_nr in 1..5 {
+            let mut packet = Packet::with_payload(&[seq_nr as u8]);
+            packet.set_seq_nr(seq_nr);
+            socket.curr_window += packet.len() as u32;
+            socket.send_window.push_back(packet.seq_nr(), SentPacket::new(packet));
+        }
+        socket.seq_nr = 5;
+
+        // Packet 3 was received, but packets 2 and 4 might still be on their way
+        let mut ack = Packet::new();
+        ack.set_type(PacketType::State);
+        ack.set_ack_nr(1);
+        ack.set_sack(vec![0b1, 0, 0, 0]);
+        socket.handle_state_packet(&ack.as_packet_ref());
+
+        let remaining = socket.send_window.iter().map(|p| p.packet.seq_nr()).collect::<Vec<_>>();
+        assert_eq!(remaining, vec![2, 4]);
+        assert!(socket.send_window.iter().all(|p| p.transmissions == 1));
+        assert_eq!(socket.cwnd, INIT_CWND * MSS);
+
+        // Mark socket as closed
+        socket.state = SocketState::Closed;
+    }
+
+    #[test]
+    fn test_bounded_selective_ack() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+        // Nothing to report
+        socket.ack_nr = ::std::u16::MAX - 1;
+        socket.incoming_buffer.reset(::std::u16::MAX);
+        assert!(socket.build_selective_ack().is_empty());
+
+        // The sequence numbers wrap around past `ack_nr`
+        let mut packet = Packet::new();
+        for &seq_nr in &[1, 31, 1000] {
+            packet.set_seq_nr(seq_nr);
+            assert!(socket.insert_into_buffer(packet.clone()).is_ok());
+        }
+
+        // Packet 1000 is too far ahead to fit in the extension and is left out
+        let sack = socket.build_selective_ack();
+        assert_eq!(sack, vec![0b10, 0, 0, 0b10000000]);
+
+        // A reply carrying it is still a valid packet
+        let mut reply = Packet::new();
+        reply.set_sack(sack);
+        let reply = iotry!(Packet::try_from(reply.as_ref()));
+        assert_eq!(reply.extensions().count(), 1);
+
+        // Only packets beyond the extension's reach are buffered
+        socket.ack_nr = 500;
+        assert!(socket.build_selective_ack().is_empty());
+    }
+
+    #[test]
+    fn test_sorted_buffer_insertion() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+
+        socket.incoming_buffer.reset(1);
+
+        let mut packet = Packet::new();
+        packet.set_seq_nr(1);
+
+        assert!(socket.incoming_buffer.is_empty());
+
+        assert!(socket.insert_into_buffer(packet.clone()).is_ok());
+        assert_eq!(socket.incoming_buffer.len(), 1);
+
+        packet.set_seq_nr(3);
+        packet.set_timestamp(256.into());
+
+        assert!(socket.insert_into_buffer(packet.clone()).is_ok());
+        assert_eq!(socket.incoming_buffer.len(), 2);
+        assert_eq!(socket.incoming_buffer.get(3).unwrap().timestamp(), 256.into());
+
+        packet.set_seq_nr(2);
+        packet.set_timestamp(128.into());
+
+        assert!(socket.insert_into_buffer(packet.clone()).is_ok());
+        assert_eq!(socket.incoming_buffer.len(), 3);
+        assert_eq!(socket.incoming_buffer.get(2).unwrap().timestamp(), 128.into());
+
+        // Replacing a packet with a more recent version doesn't work
+        packet.set_seq_nr(2);
+        packet.set_timestamp(456.into());
+
+        assert!(socket.insert_into_buffer(packet.clone()).is_err());
+        assert_eq!(socket.incoming_buffer.len(), 3);
+        assert_eq!(socket.incoming_buffer.get(2).unwrap().timestamp(), 128.into());
+
+        // Packets are read in order
+        for seq_nr in 1..4 {
+            assert_eq!(socket.advance_incoming_buffer().map(|p| p.seq_nr()), Some(seq_nr));
+        }
+        assert!(socket.incoming_buffer.is_empty());
+
+        // Packets already read are ignored
+        packet.set_seq_nr(3);
+        assert!(socket.insert_into_buffer(packet.clone()).is_err());
+        assert!(socket.incoming_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_drop_packets_too_far_ahead() {
+        use socket::INCOMING_BUFFER_SIZE;
+
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        socket.incoming_buffer.reset(1);
+
+        let mut packet = Packet::with_payload(&[1]);
+        packet.set_seq_nr(INCOMING_BUFFER_SIZE as u16);
+        assert!(socket.insert_into_buffer(packet.clone()).is_ok());
+        assert_eq!(socket.incoming_buffer.len(), 1);
+
+        // Just past the end of the buffer
+        packet.set_seq_nr(INCOMING_BUFFER_SIZE as u16 + 1);
+        assert!(socket.insert_into_buffer(packet.clone()).is_err());
+        assert_eq!(socket.incoming_buffer.len(), 1);
+
+        // Nothing can be read until the gap closes
+        let mut buf = [0; BUF_SIZE];
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 0);
+    }
+
+    #[test]
+    fn test_partial_reads_from_buffer() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        socket.incoming_buffer.reset(1);
+
+        for seq_nr in 1..3 {
+            let mut packet = Packet::with_payload(&[seq_nr as u8; 5]);
+            packet.set_seq_nr(seq_nr);
+            assert!(socket.insert_into_buffer(packet).is_ok());
+        }
+
+        let mut buf = [0; 3];
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 3);
+        assert_eq!(buf, [1, 1, 1]);
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 3);
+        assert_eq!(buf, [1, 1, 2]);
+        assert_eq!(socket.ack_nr, 1);
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 3);
+        assert_eq!(buf, [2, 2, 2]);
+        assert_eq!(socket.incoming_buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_read_fills_buffer_from_contiguous_packets() {
+        let server_addr = next_test_ip4();
+        let mut socket = iotry!(UtpSocket::bind(server_addr));
+        socket.incoming_buffer.reset(1);
+
+        // Packets 1 to 3 are in order, packet 4 is missing
+        for seq_nr in (1..4).chain(5..6) {
+            let mut packet = Packet::with_payload(&[seq_nr as u8; 4]);
+            packet.set_seq_nr(seq_nr);
+            assert!(socket.insert_into_buffer(packet).is_ok());
+        }
+
+        let mut buf = [0; 64];
+        let (read, _) = iotry!(socket.recv_from(&mut buf));
+        assert_eq!(read, 12);
+        assert_eq!(&buf[..read], &[1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+        assert_eq!(socket.ack_nr, 3);
+
+        // The packet after the gap stays buffered
+        assert_eq!(socket.flush_incoming_buffer(&mut buf), 0);
+        assert_eq!(socket.incoming_buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_packet_handling() {
+        let (server_addr, client_addr) = (next_test_ip4(), next_test_ip4());
+
+        let client = iotry!(UtpSocket::bind(client_addr));
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+
+        assert_eq!(server.state, SocketState::New);
+        assert_eq!(client.state, SocketState::New);
+
+        // Check proper difference in client's send connection id and receive connection id
+        assert_eq!(client.sender_connection_id, client.receiver_connection_id + 1);
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+            assert_eq!(client.state, SocketState::Connected);
+
+            let mut packet = Packet::with_payload(&[1, 2, 3]);
+            packet.set_wnd_size(BUF_SIZE as u32);
+            packet.set_connection_id(client.sender_connection_id);
+            packet.set_seq_nr(client.seq_nr);
+            packet.set_ack_nr(client.ack_nr);
+
+            // Send two copies of the packet, with different timestamps
+            for _ in 0..2 {
+                packet.set_timestamp(now_microseconds());
+                iotry!(client.socket.send_to(packet.as_ref(), server_addr));
+            }
+            client.seq_nr += 1;
+
+            // Receive one ACK
+            for _ in 0..1 {
+                let mut buf = [0; BUF_SIZE];
+                iotry!(client.socket.recv_from(&mut buf));
+            }
+
+            iotry!(client.close());
+        });
+
+        let mut buf = [0u8; BUF_SIZE];
+        iotry!(server.recv(&mut buf));
+        // After establishing a new connection, the server's ids are a mirror of the client's.
+        assert_eq!(server.receiver_connection_id, server.sender_connection_id + 1);
+
+        assert_eq!(server.state, SocketState::Connected);
+
+        let expected: Vec<u8> = vec![1, 2, 3];
+        let mut received: Vec<u8> = vec![];
+        loop {
+            match server.recv_from(&mut buf) {
+                Ok((0, _src)) => break,
+                Ok((len, _src)) => received.extend(buf[..len].to_vec()),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        assert_eq!(received.len(), expected.len());
+        assert_eq!(received, expected);
+
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn test_correct_packet_loss() {
+        let server_addr = next_test_ip4();
+
+        let mut server = iotry!(UtpSocket::bind(server_addr));
+        const LEN: usize = 1024 * 10;
+        let data = (0..LEN).map(|idx| idx as u8).collect::<Vec<u8>>();
+        let to_send = data.clone();
+
+        let child = thread::spawn(move || {
+            let mut client = iotry!(UtpSocket::connect(server_addr));
+
+            // Send everything except the odd chunks
             let chunks = to_send[..].chunks(BUF_SIZE);
             let dst = client.connected_to;
             for (index, chunk) in chunks.enumerate() {
@@ -1860,7 +4317,7 @@ mod test {
                 }
 
                 client.curr_window += packet.len() as u32;
-                client.send_window.push(packet);
+                client.send_window.push_back(packet.seq_nr(), SentPacket::new(packet));
                 client.seq_nr += 1;
             }
 
@@ -1976,8 +4433,11 @@ mod test {
         });
 
         match UtpSocket::connect(server_addr) {
-            Err(ref e) if e.kind() == ErrorKind::Other => (), // OK
-            Err(e) => panic!("Expected ErrorKind::Other, got {:?}", e),
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                let error = e.get_ref().and_then(|e| e.downcast_ref::<UtpError>());
+                assert_eq!(error, Some(&UtpError::InvalidPacket(ParseError::InvalidPacketLength)));
+            }
+            Err(e) => panic!("Expected ErrorKind::InvalidData, got {:?}", e),
             Ok(_) => panic!("Expected Err, got Ok"),
         }
 
@@ -2004,8 +4464,39 @@ mod test {
         });
 
         match UtpSocket::connect(server_addr) {
-            Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => (), // OK
-            Err(e) => panic!("Expected ErrorKind::ConnectionRefused, got {:?}", e),
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                let error = e.get_ref().and_then(|e| e.downcast_ref::<UtpError>());
+                assert_eq!(error, Some(&UtpError::InvalidReply));
+            }
+            Err(e) => panic!("Expected ErrorKind::InvalidData, got {:?}", e),
+            Ok(_) => panic!("Expected Err, got Ok"),
+        }
+
+        assert!(child.join().is_ok());
+    }
+
+    #[test]
+    fn test_connection_id_mismatch_on_connect() {
+        use std::net::UdpSocket;
+        let server_addr = next_test_ip4();
+        let server = iotry!(UdpSocket::bind(server_addr));
+
+        let child = thread::spawn(move || {
+            let mut buf = [0; BUF_SIZE];
+            let (len, client_addr) = iotry!(server.recv_from(&mut buf));
+            let syn = iotry!(Packet::try_from(&buf[..len]));
+            let mut packet = Packet::new();
+            packet.set_type(PacketType::State);
+            packet.set_connection_id(syn.connection_id().wrapping_add(1));
+            iotry!(server.send_to(packet.as_ref(), client_addr));
+        });
+
+        match UtpSocket::connect(server_addr) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                let error = e.get_ref().and_then(|e| e.downcast_ref::<UtpError>());
+                assert_eq!(error, Some(&UtpError::ConnectionIdMismatch));
+            }
+            Err(e) => panic!("Expected ErrorKind::InvalidData, got {:?}", e),
             Ok(_) => panic!("Expected Err, got Ok"),
         }
 
@@ -2350,3 +4841,76 @@ mod test {
         assert!(child.join().is_ok());
     }
 }
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+
+    use self::test::Bencher;
+    use std::net::UdpSocket;
+    use socket::{UtpSocket, SocketState, SentPacket};
+    use packet::{Packet, PacketType};
+
+    // Packets in flight, enough for scanning or shifting the send window to show
+    const WINDOW_LEN: u16 = 2048;
+
+    /// Returns a socket with `WINDOW_LEN` packets in flight, numbered from 1, along with the silent
+    /// peer it's connected to.
+    fn socket_with_full_send_window() -> (UtpSocket, UdpSocket) {
+        let mut socket = UtpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connected_to = peer.local_addr().unwrap();
+        socket.state = SocketState::Connected;
+        // Lost packets are never old enough to be resent
+        socket.rtt = 60_000;
+
+        for seq_nr in 1..WINDOW_LEN + 1 {
+            let mut packet = Packet::with_payload(&[0; 100]);
+            packet.set_seq_nr(seq_nr);
+            socket.curr_window += packet.len() as u32;
+            socket.send_window.push_back(seq_nr, SentPacket::new(packet));
+        }
+        socket.seq_nr = WINDOW_LEN + 1;
+        (socket, peer)
+    }
+
+    fn state_packet(ack_nr: u16) -> Packet {
+        let mut packet = Packet::new();
+        packet.set_type(PacketType::State);
+        packet.set_ack_nr(ack_nr);
+        packet
+    }
+
+    #[bench]
+    fn bench_cumulative_acks(b: &mut Bencher) {
+        b.iter(|| {
+            let (mut socket, _peer) = socket_with_full_send_window();
+
+            // Every other packet is acknowledged, as with delayed acknowledgements
+            for ack_nr in (1..WINDOW_LEN / 2 + 1).map(|n| n * 2) {
+                socket.handle_state_packet(&state_packet(ack_nr).as_packet_ref());
+            }
+            assert!(socket.send_window.is_empty());
+            socket.state = SocketState::Closed;
+        });
+    }
+
+    #[bench]
+    fn bench_selective_acks(b: &mut Bencher) {
+        b.iter(|| {
+            let (mut socket, _peer) = socket_with_full_send_window();
+
+            // The first packet is lost, and each acknowledgement selectively acknowledges eight
+            // more of the packets following it
+            for len in 1..253 {
+                let mut sack = vec![0xff; len];
+                sack.resize((len + 3) / 4 * 4, 0);
+                let mut packet = state_packet(0);
+                packet.set_sack(sack);
+                socket.handle_state_packet(&packet.as_packet_ref());
+            }
+            assert_eq!(socket.send_window.len(), WINDOW_LEN as usize - 252 * 8);
+            socket.state = SocketState::Closed;
+        });
+    }
+}
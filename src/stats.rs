@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+/// A snapshot of a socket's counters and congestion control state, as returned by
+/// `UtpSocket::stats`.
+///
+/// Byte and packet counters cover whole datagrams, headers included, and never decrease over the
+/// socket's lifetime.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketStats {
+    /// Bytes sent, retransmissions included
+    pub bytes_sent: u64,
+
+    /// Bytes received, dropped packets included
+    pub bytes_received: u64,
+
+    /// Packets sent, retransmissions included
+    pub packets_sent: u64,
+
+    /// Packets received, dropped packets included
+    pub packets_received: u64,
+
+    /// Packets sent more than once, after a timeout or a loss report
+    pub retransmissions: u64,
+
+    /// Packets resent after being reported lost by duplicate or selective acknowledgements,
+    /// without waiting for a timeout
+    pub fast_retransmits: u64,
+
+    /// Expirations of the retransmission timer
+    pub timeouts: u64,
+
+    /// Data packets received more than once
+    pub duplicate_packets: u64,
+
+    /// Acknowledgements carrying a selective acknowledgement extension
+    pub sacks_received: u64,
+
+    /// Smoothed round-trip time estimate
+    pub rtt: Duration,
+
+    /// Variance of the round-trip time
+    pub rtt_variance: Duration,
+
+    /// Current retransmission timeout
+    pub rto: Duration,
+
+    /// Congestion window in bytes
+    pub cwnd: u32,
+
+    /// Bytes sent but not yet acknowledged
+    pub flight_size: u32,
+
+    /// Receive window advertised by the remote peer, in bytes
+    pub peer_window: u32,
+
+    /// Estimated queuing delay along the path to the remote peer
+    pub queuing_delay: Duration,
+}
@@ -1,12 +1,19 @@
 use std::io::{Read, Write, Result};
 use std::net::{ToSocketAddrs, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use socket::UtpSocket;
+use stats::SocketStats;
+use rate_limit::RateLimiter;
+use capture::PacketCapture;
+use events::EventHandler;
+use observer::PacketObserver;
 
 /// A structure that represents a uTP (Micro Transport Protocol) stream between a local socket and a
 /// remote socket.
 ///
 /// The connection will be closed when the value is dropped (either explicitly or when it goes out
-/// of scope).
+/// of scope), according to the policy set with `set_linger`.
 ///
 /// The default maximum retransmission retries is 5, which translates to about 16 seconds. It can be
 /// changed by calling `set_max_retransmission_retries`. Notice that the initial congestion timeout
@@ -65,6 +72,85 @@ impl UtpStream {
     pub fn set_max_retransmission_retries(&mut self, n: u32) {
         self.socket.max_retransmission_retries = n;
     }
+
+    /// Limits the rate at which the underlying socket sends data.
+    ///
+    /// See `UtpSocket::set_rate_limiter` for details.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.socket.set_rate_limiter(limiter);
+    }
+
+    /// Limits the rate at which the underlying socket receives data.
+    ///
+    /// See `UtpSocket::set_recv_rate_limiter` for details.
+    pub fn set_recv_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.socket.set_recv_rate_limiter(limiter);
+    }
+
+    /// Enables or disables packet pacing on the underlying socket.
+    ///
+    /// See `UtpSocket::set_pacing` for details.
+    pub fn set_pacing(&mut self, enabled: bool) {
+        self.socket.set_pacing(enabled);
+    }
+
+    /// Sets the size in bytes of the underlying socket's send buffer.
+    ///
+    /// See `UtpSocket::set_send_buffer_size` for details.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.socket.set_send_buffer_size(size);
+    }
+
+    /// Moves writes on the underlying socket into or out of non-blocking mode.
+    ///
+    /// See `UtpSocket::set_nonblocking` for details.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.socket.set_nonblocking(nonblocking);
+    }
+
+    /// Records every datagram the underlying socket sends and receives into a pcap capture.
+    ///
+    /// See `UtpSocket::set_capture` for details.
+    pub fn set_capture(&mut self, capture: Option<PacketCapture>) {
+        self.socket.set_capture(capture);
+    }
+
+    /// Registers an observer notified of every packet the underlying socket sends, receives or
+    /// drops.
+    ///
+    /// See `UtpSocket::set_observer` for details.
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn PacketObserver>>) {
+        self.socket.set_observer(observer);
+    }
+
+    /// Registers a handler notified of the lifecycle events of the underlying connection.
+    ///
+    /// See `UtpSocket::set_event_handler` for details.
+    pub fn set_event_handler(&mut self, handler: Option<Arc<dyn EventHandler>>) {
+        self.socket.set_event_handler(handler);
+    }
+
+    /// Aborts the connection immediately, with a `Reset` packet.
+    ///
+    /// See `UtpSocket::abort` for details.
+    pub fn abort(&mut self) -> Result<()> {
+        self.socket.abort()
+    }
+
+    /// Sets what happens to the connection when the stream is dropped without being closed.
+    ///
+    /// See `UtpSocket::set_linger` for details.
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.socket.set_linger(linger);
+    }
+
+    /// Returns a snapshot of the underlying socket's traffic counters and congestion control
+    /// state.
+    ///
+    /// See `UtpSocket::stats` for details.
+    pub fn stats(&self) -> SocketStats {
+        self.socket.stats()
+    }
 }
 
 impl Read for UtpStream {
@@ -85,7 +171,7 @@ impl Write for UtpStream {
 
 impl From<UtpSocket> for UtpStream {
     fn from(socket: UtpSocket) -> Self {
-        UtpStream { socket: socket }
+        UtpStream { socket }
     }
 }
 
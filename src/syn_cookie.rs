@@ -0,0 +1,77 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+// Seconds during which a cookie is issued unchanged; cookies stay valid for one more period
+const COOKIE_PERIOD: u64 = 32;
+
+/// Issues and checks the initial sequence numbers of stateless handshakes.
+///
+/// A cookie is a randomly keyed SipHash of the remote peer's address, the connection id and
+/// sequence number of its SYN and the current time period, truncated to the 16 bits of a sequence
+/// number. Only someone who received the reply to the SYN can echo it back, short of guessing it
+/// out of 65536 values.
+pub struct CookieJar {
+    key: RandomState,
+    created_at: Instant,
+}
+
+impl CookieJar {
+    /// Creates a jar with a new random key.
+    pub fn new() -> CookieJar {
+        CookieJar {
+            key: RandomState::new(),
+            created_at: Instant::now(),
+        }
+    }
+
+    /// Returns the cookie for a SYN received from `src`.
+    pub fn issue(&self, src: SocketAddr, connection_id: u16, seq_nr: u16) -> u16 {
+        self.mac(src, connection_id, seq_nr, self.period())
+    }
+
+    /// Checks a cookie echoed by `src`, for the SYN with the given connection id and sequence
+    /// number.
+    pub fn check(&self, src: SocketAddr, connection_id: u16, seq_nr: u16, cookie: u16) -> bool {
+        let period = self.period();
+        cookie == self.mac(src, connection_id, seq_nr, period) ||
+            (period > 0 && cookie == self.mac(src, connection_id, seq_nr, period - 1))
+    }
+
+    fn period(&self) -> u64 {
+        self.created_at.elapsed().as_secs() / COOKIE_PERIOD
+    }
+
+    fn mac(&self, src: SocketAddr, connection_id: u16, seq_nr: u16, period: u64) -> u16 {
+        self.key.hash_one((src, connection_id, seq_nr, period)) as u16
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use syn_cookie::CookieJar;
+
+    #[test]
+    fn test_cookie() {
+        let jar = CookieJar::new();
+        let src: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:6882".parse().unwrap();
+
+        let cookie = jar.issue(src, 1234, 42);
+        assert_eq!(jar.issue(src, 1234, 42), cookie);
+        assert!(jar.check(src, 1234, 42, cookie));
+        assert!(!jar.check(src, 1234, 42, cookie.wrapping_add(1)));
+
+        // Any change to the handshake invalidates the cookie, barring collisions
+        assert!((1..100).filter(|&id| jar.check(src, 1234 + id, 42, cookie)).count() < 5);
+        assert!((1..100).filter(|&seq_nr| jar.check(src, 1234, 42 + seq_nr, cookie)).count() < 5);
+        assert!(!jar.check(other, 1234, 42, cookie) || !jar.check(other, 1234, 43, cookie));
+
+        // Cookies are keyed
+        let other_jar = CookieJar::new();
+        assert!((0..100).filter(|&id| jar.issue(src, id, 42) == other_jar.issue(src, id, 42))
+                        .count() < 5);
+    }
+}
@@ -11,6 +11,7 @@ pub fn now_microseconds() -> Timestamp {
     (t.as_secs().wrapping_mul(1_000_000) as u32).wrapping_add(t.subsec_nanos() / 1000).into()
 }
 
+/// A point in time, in microseconds. It wraps around every 71 minutes or so.
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
 pub struct Timestamp(pub u32);
 
@@ -40,6 +41,7 @@ impl From<Timestamp> for u32 {
     }
 }
 
+/// A signed difference between two timestamps, in microseconds.
 #[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq)]
 pub struct Delay(pub i64);
 
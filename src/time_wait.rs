@@ -0,0 +1,164 @@
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use packet::{Packet, PacketRef, PacketType, TryFrom};
+use time::now_microseconds;
+use util::abs_diff;
+
+// Large enough for any datagram a uTP peer sends
+const BUF_SIZE: usize = 1500;
+
+// Milliseconds between two polls of the sockets of closed connections
+const POLL_INTERVAL: u64 = 50;
+
+/// What is left of a gracefully closed connection: just enough to answer the remote peer's late
+/// packets.
+pub struct ClosedConnection {
+    /// Address of the remote peer
+    pub peer: SocketAddr,
+
+    /// Connection id of the packets received from the remote peer
+    pub receiver_connection_id: u16,
+
+    /// Connection id of the packets sent to the remote peer
+    pub sender_connection_id: u16,
+
+    /// Sequence number of the next packet the connection would have sent
+    pub seq_nr: u16,
+
+    /// Last sequence number acknowledged to the remote peer
+    pub ack_nr: u16,
+}
+
+impl ClosedConnection {
+    /// Returns the reply to a datagram received from `src`, if any.
+    ///
+    /// Retransmitted `Fin` packets are acknowledged again with a `State` packet, in case the
+    /// original acknowledgement was lost, and late data is answered with a `Reset`. Anything else,
+    /// including packets of other connections, is ignored.
+    fn reply(&mut self, datagram: &[u8], src: SocketAddr) -> Option<Packet> {
+        let packet = match PacketRef::try_from(datagram) {
+            Ok(packet) => packet,
+            Err(_) => return None,
+        };
+        if src != self.peer || packet.connection_id() != self.receiver_connection_id {
+            return None;
+        }
+
+        let reply_type = match packet.get_type() {
+            PacketType::Fin => {
+                // The remote peer may only send its FIN after ours was acknowledged
+                if packet.seq_nr().wrapping_sub(self.ack_nr) == 1 {
+                    self.ack_nr = packet.seq_nr();
+                }
+                PacketType::State
+            }
+            PacketType::Data => PacketType::Reset,
+            _ => return None,
+        };
+
+        let now = now_microseconds();
+        let mut reply = Packet::new();
+        reply.set_type(reply_type);
+        reply.set_timestamp(now);
+        reply.set_timestamp_difference(abs_diff(now, packet.timestamp()));
+        reply.set_connection_id(self.sender_connection_id);
+        reply.set_seq_nr(self.seq_nr);
+        reply.set_ack_nr(self.ack_nr);
+        Some(reply)
+    }
+}
+
+/// Gracefully closed connections kept open for a while to answer the remote peers' late
+/// packets.
+///
+/// Cloning a `TimeWait` yields a new handle to the same set of connections. A single background
+/// thread serves all of them, polling their sockets, and only runs while some connection is
+/// waiting.
+#[derive(Clone)]
+pub struct TimeWait {
+    duration: Duration,
+    waiting: Arc<Mutex<Waiting>>,
+}
+
+struct Waiting {
+    /// Connections still waiting, along with their sockets and when they may be released
+    connections: Vec<(UdpSocket, ClosedConnection, Instant)>,
+
+    /// Whether the background thread is running
+    running: bool,
+}
+
+impl TimeWait {
+    /// Creates an empty set, whose connections wait for `duration`.
+    pub fn new(duration: Duration) -> TimeWait {
+        TimeWait {
+            duration,
+            waiting: Arc::new(Mutex::new(Waiting {
+                connections: Vec::new(),
+                running: false,
+            })),
+        }
+    }
+
+    /// Keeps `socket` open to answer the late packets of the connection that just closed on it.
+    pub fn insert(&self, socket: UdpSocket, connection: ClosedConnection) {
+        if socket.set_nonblocking(true).is_err() {
+            return;
+        }
+
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.connections.push((socket, connection, Instant::now() + self.duration));
+        if !waiting.running {
+            waiting.running = true;
+            let time_wait = self.clone();
+            thread::spawn(move || time_wait.run());
+        }
+    }
+
+    /// Answers late packets until no connection is left waiting.
+    fn run(&self) {
+        let mut buf = [0; BUF_SIZE];
+        loop {
+            {
+                let mut waiting = self.waiting.lock().unwrap();
+                let now = Instant::now();
+                waiting.connections.retain(|&(_, _, deadline)| deadline > now);
+                for &mut (ref socket, ref mut connection, ref mut deadline) in
+                    &mut waiting.connections {
+                    if !answer(socket, connection, &mut buf) {
+                        *deadline = now;
+                    }
+                }
+                if waiting.connections.is_empty() {
+                    waiting.running = false;
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL));
+        }
+    }
+}
+
+/// Answers the datagrams queued on the socket of a closed connection.
+///
+/// Returns `false` if the socket failed, in which case the connection stops waiting.
+fn answer(socket: &UdpSocket, connection: &mut ClosedConnection, buf: &mut [u8]) -> bool {
+    loop {
+        match socket.recv_from(buf) {
+            Ok((len, src)) => {
+                if let Some(reply) = connection.reply(&buf[..len], src) {
+                    debug!("replying to late packet with {:?}", reply);
+                    let _ = socket.send_to(reply.as_ref(), src);
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return true,
+            Err(e) => {
+                debug!("releasing closed connection to {}: {}", connection.peer, e);
+                return false;
+            }
+        }
+    }
+}
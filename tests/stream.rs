@@ -70,7 +70,7 @@ fn test_stream_small_data() {
 
     let child = thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
-        iotry!(client.write(&d[..]));
+        iotry!(client.write_all(&d[..]));
         iotry!(client.close());
     });
 
@@ -95,7 +95,7 @@ fn test_stream_large_data() {
 
     let child = thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
-        iotry!(client.write(&d[..]));
+        iotry!(client.write_all(&d[..]));
         iotry!(client.close());
     });
 
@@ -119,7 +119,7 @@ fn test_stream_successive_reads() {
 
     let child = thread::spawn(move || {
         let mut client = iotry!(UtpStream::connect(server_addr));
-        iotry!(client.write(&d[..]));
+        iotry!(client.write_all(&d[..]));
         iotry!(client.close());
     });
 